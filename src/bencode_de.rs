@@ -0,0 +1,411 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::de::{
+    self, DeserializeSeed, Deserializer as _, EnumAccess, MapAccess, SeqAccess, VariantAccess,
+    Visitor,
+};
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+
+use crate::bencode_decode::{decode_bencoded_ref, BenValue, BencodeError, PathSegment};
+use crate::bencode_ref::{BenRef, BenRefKind, RawBencode, RAW_BENCODE_TOKEN};
+
+impl de::Error for BencodeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        BencodeError::custom(msg)
+    }
+}
+
+/// Deserializes a single bencoded value into `T`, borrowing byte strings
+/// from the input where `T` allows it.
+pub fn from_bytes<'de, T: Deserialize<'de>>(bytes: &'de [u8]) -> Result<T, BencodeError> {
+    let value = decode_bencoded_ref(bytes)?;
+
+    if let Some(&b) = bytes.get(value.span.end) {
+        return Err(BencodeError::UnexpectedByte {
+            offset: value.span.end,
+            found: b,
+            expected: "end of input",
+            path: String::new(),
+        });
+    }
+
+    from_ref(&value)
+}
+
+/// Deserializes an already parsed value, e.g. one entry of a larger
+/// dictionary.
+pub fn from_ref<'de, T: Deserialize<'de>>(value: &BenRef<'de>) -> Result<T, BencodeError> {
+    T::deserialize(Deserializer { value })
+}
+
+pub struct Deserializer<'a, 'de> {
+    value: &'a BenRef<'de>,
+}
+
+impl<'a, 'de> Deserializer<'a, 'de> {
+    fn invalid_type(&self, expected: &dyn de::Expected) -> BencodeError {
+        let unexpected = match &self.value.kind {
+            BenRefKind::Bytes(bytes) => de::Unexpected::Bytes(bytes),
+            BenRefKind::Int(integer) => de::Unexpected::Signed(*integer),
+            BenRefKind::List(_) => de::Unexpected::Seq,
+            BenRefKind::Dict(_) => de::Unexpected::Map,
+        };
+
+        de::Error::invalid_type(unexpected, expected)
+    }
+}
+
+impl<'a, 'de> de::Deserializer<'de> for Deserializer<'a, 'de> {
+    type Error = BencodeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        match &self.value.kind {
+            BenRefKind::Bytes(bytes) => visitor.visit_borrowed_bytes(bytes),
+            BenRefKind::Int(integer) => visitor.visit_i64(*integer),
+            BenRefKind::List(list) => visitor.visit_seq(ListAccess {
+                iter: list.iter().enumerate(),
+            }),
+            BenRefKind::Dict(entries) => visitor.visit_map(DictAccess {
+                iter: entries.iter(),
+                value: None,
+            }),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        match self.value.kind {
+            BenRefKind::Int(0) => visitor.visit_bool(false),
+            BenRefKind::Int(1) => visitor.visit_bool(true),
+            _ => Err(self.invalid_type(&visitor)),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        match self.value.kind {
+            BenRefKind::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                Ok(string) => visitor.visit_borrowed_str(string),
+                Err(_) => Err(de::Error::invalid_value(
+                    de::Unexpected::Bytes(bytes),
+                    &"a UTF-8 string",
+                )),
+            },
+            _ => Err(self.invalid_type(&visitor)),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        // bencode has no null; an absent dictionary key is how None is spelled
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        Err(self.invalid_type(&visitor))
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        if name == RAW_BENCODE_TOKEN {
+            return visitor.visit_borrowed_bytes(self.value.raw());
+        }
+
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        match &self.value.kind {
+            // unit variants are spelled as their name, everything else as a
+            // dictionary with the variant name as its only key
+            BenRefKind::Bytes(_) => visitor.visit_enum(Enum {
+                variant: self.value,
+                value: None,
+            }),
+            BenRefKind::Dict(entries) if entries.len() == 1 => visitor.visit_enum(Enum {
+                variant: self.value,
+                value: Some(&entries[0]),
+            }),
+            _ => Err(self.invalid_type(&visitor)),
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 bytes byte_buf
+        seq tuple tuple_struct map struct
+    }
+}
+
+struct ListAccess<'a, 'de> {
+    iter: std::iter::Enumerate<std::slice::Iter<'a, BenRef<'de>>>,
+}
+
+impl<'a, 'de> SeqAccess<'de> for ListAccess<'a, 'de> {
+    type Error = BencodeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, BencodeError> {
+        match self.iter.next() {
+            Some((index, value)) => seed
+                .deserialize(Deserializer { value })
+                .map(Some)
+                .map_err(|err| err.nested(PathSegment::Index(index), value.span.start)),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct DictAccess<'a, 'de> {
+    iter: std::slice::Iter<'a, (&'de [u8], BenRef<'de>)>,
+    value: Option<&'a (&'de [u8], BenRef<'de>)>,
+}
+
+impl<'a, 'de> MapAccess<'de> for DictAccess<'a, 'de> {
+    type Error = BencodeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, BencodeError> {
+        match self.iter.next() {
+            Some(entry) => {
+                self.value = Some(entry);
+                seed.deserialize(KeyDeserializer { key: entry.0 }).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, BencodeError> {
+        let (key, value) = self
+            .value
+            .take()
+            .ok_or_else(|| BencodeError::custom("value requested before key"))?;
+
+        seed.deserialize(Deserializer { value })
+            .map_err(|err| err.nested(PathSegment::Key(key), value.span.start))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct KeyDeserializer<'de> {
+    key: &'de [u8],
+}
+
+impl<'de> de::Deserializer<'de> for KeyDeserializer<'de> {
+    type Error = BencodeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        match std::str::from_utf8(self.key) {
+            Ok(string) => visitor.visit_borrowed_str(string),
+            Err(_) => visitor.visit_borrowed_bytes(self.key),
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        visitor.visit_borrowed_bytes(self.key)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        visitor.visit_borrowed_bytes(self.key)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        option unit unit_struct newtype_struct seq tuple tuple_struct map struct
+        enum identifier ignored_any
+    }
+}
+
+struct Enum<'a, 'de> {
+    variant: &'a BenRef<'de>,
+    value: Option<&'a (&'de [u8], BenRef<'de>)>,
+}
+
+impl<'a, 'de> EnumAccess<'de> for Enum<'a, 'de> {
+    type Error = BencodeError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self), BencodeError> {
+        let name = match (self.value, &self.variant.kind) {
+            (Some((key, _)), _) => *key,
+            (None, BenRefKind::Bytes(name)) => name,
+            _ => return Err(BencodeError::custom("expected an enum variant")),
+        };
+
+        let variant = seed.deserialize(KeyDeserializer { key: name })?;
+        Ok((variant, self))
+    }
+}
+
+impl<'a, 'de> VariantAccess<'de> for Enum<'a, 'de> {
+    type Error = BencodeError;
+
+    fn unit_variant(self) -> Result<(), BencodeError> {
+        match self.value {
+            None => Ok(()),
+            Some(_) => Err(BencodeError::custom("expected a unit variant")),
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, BencodeError> {
+        match self.value {
+            Some((_, value)) => seed.deserialize(Deserializer { value }),
+            None => Err(BencodeError::custom("expected a newtype variant")),
+        }
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        match self.value {
+            Some((_, value)) => Deserializer { value }.deserialize_seq(visitor),
+            None => Err(BencodeError::custom("expected a tuple variant")),
+        }
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        match self.value {
+            Some((_, value)) => Deserializer { value }.deserialize_map(visitor),
+            None => Err(BencodeError::custom("expected a struct variant")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for BenValue {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<BenValue, D::Error> {
+        deserializer.deserialize_any(BenValueVisitor)
+    }
+}
+
+struct BenValueVisitor;
+
+impl<'de> Visitor<'de> for BenValueVisitor {
+    type Value = BenValue;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a bencode value")
+    }
+
+    fn visit_bool<E: de::Error>(self, value: bool) -> Result<BenValue, E> {
+        Ok(BenValue::Int(value as i64))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<BenValue, E> {
+        Ok(BenValue::Int(value))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<BenValue, E> {
+        i64::try_from(value)
+            .map(BenValue::Int)
+            .map_err(|_| E::invalid_value(de::Unexpected::Unsigned(value), &self))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<BenValue, E> {
+        Ok(BenValue::Bytes(value.as_bytes().to_vec()))
+    }
+
+    fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<BenValue, E> {
+        Ok(BenValue::Bytes(value.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, value: Vec<u8>) -> Result<BenValue, E> {
+        Ok(BenValue::Bytes(value))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<BenValue, A::Error> {
+        let mut list = Vec::new();
+        while let Some(value) = seq.next_element()? {
+            list.push(value);
+        }
+
+        Ok(BenValue::List(list))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<BenValue, A::Error> {
+        let mut dict = BTreeMap::new();
+        while let Some((key, value)) = map.next_entry::<ByteBuf, BenValue>()? {
+            dict.insert(key.into_vec(), value);
+        }
+
+        Ok(BenValue::Dict(dict))
+    }
+}
+
+impl<'de> Deserialize<'de> for RawBencode {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<RawBencode, D::Error> {
+        deserializer.deserialize_newtype_struct(RAW_BENCODE_TOKEN, RawBencodeVisitor)
+    }
+}
+
+struct RawBencodeVisitor;
+
+impl<'de> Visitor<'de> for RawBencodeVisitor {
+    type Value = RawBencode;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a raw bencode value")
+    }
+
+    fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<RawBencode, E> {
+        Ok(RawBencode(value.to_vec()))
+    }
+}
@@ -1,169 +1,610 @@
 use serde_json::{Map, Value};
-use std::result::Result::Ok;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
 use std::str::{self, FromStr};
 
+use crate::bencode_ref::{BenRef, BenRefKind};
+use crate::bencode_stream::DecodeLimits;
+
+/// A decoded bencode value.
+///
+/// Byte strings are kept as raw bytes (dictionary keys included), so binary
+/// data such as piece hashes and compact peer lists survive decoding intact.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum BenValue {
-    BenString(String),
-    BenInteger(i64),
-    BenList(Vec<BenValue>),
-    BenDict(Map<String, Value>),
+    Bytes(Vec<u8>),
+    Int(i64),
+    List(Vec<BenValue>),
+    Dict(BTreeMap<Vec<u8>, BenValue>),
 }
 
-pub fn decode_bencoded_values(encoded_values: &[u8]) -> Value {
-    let mut values = Vec::new();
-    let mut index = 0;
+/// Prefix marking a hex-encoded byte string in JSON.
+pub const HEX_PREFIX: &str = "hex:";
+/// Prefix marking a base64-encoded byte string in JSON.
+pub const BASE64_PREFIX: &str = "base64:";
+
+/// How byte strings that are not valid UTF-8 are rendered as JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BytesRendering {
+    /// Bytes that are not UTF-8 escaped as `\xNN`; `\` is doubled in
+    /// every string.
+    Escaped,
+    /// Written as `hex:` followed by the bytes in hex. UTF-8 strings that
+    /// happen to start with `hex:` or `base64:` are hex-rendered too, so the
+    /// output stays unambiguous.
+    Hex,
+}
 
-    while index < encoded_values.len() {
-        let (value, new_index) = decode_bencoded(encoded_values, index).unwrap();
-        values.push(value);
-        index += new_index;
+impl BenValue {
+    /// Projects the value onto JSON.
+    ///
+    /// This is lossy: UTF-8 text is kept as is, bytes that are not UTF-8
+    /// are escaped as `\xNN`, and `\` is doubled in every string so the
+    /// two can't be confused.
+    pub fn to_json(&self) -> Value {
+        self.to_json_with(BytesRendering::Escaped)
+    }
+
+    pub fn to_json_with(&self, rendering: BytesRendering) -> Value {
+        match self {
+            BenValue::Bytes(bytes) => Value::String(render_bytes(bytes, rendering)),
+            BenValue::Int(integer) => Value::Number((*integer).into()),
+            BenValue::List(list) => Value::Array(
+                list.iter()
+                    .map(|value| value.to_json_with(rendering))
+                    .collect(),
+            ),
+            BenValue::Dict(dict) => {
+                let mut map = Map::new();
+                for (key, value) in dict {
+                    map.insert(render_bytes(key, rendering), value.to_json_with(rendering));
+                }
+                Value::Object(map)
+            }
+        }
     }
 
-    to_json(values)
+    /// Looks up a nested value by a JSONPath-like selector such as
+    /// `info.files[0].length` or `$["piece length"]`.
+    pub fn select(&self, path: &str) -> Option<&BenValue> {
+        let mut current = self;
+        let mut rest = path.strip_prefix('$').unwrap_or(path);
+
+        while !rest.is_empty() {
+            if let Some(bracketed) = rest.strip_prefix('[') {
+                let end = bracketed.find(']')?;
+                let inner = &bracketed[..end];
+                rest = &bracketed[end + 1..];
+
+                let quoted = inner
+                    .strip_prefix('"')
+                    .and_then(|key| key.strip_suffix('"'))
+                    .or_else(|| {
+                        inner
+                            .strip_prefix('\'')
+                            .and_then(|key| key.strip_suffix('\''))
+                    });
+
+                current = match (current, quoted) {
+                    (BenValue::Dict(dict), Some(key)) => dict.get(key.as_bytes())?,
+                    (BenValue::List(list), None) => list.get(inner.parse::<usize>().ok()?)?,
+                    _ => return None,
+                };
+            } else {
+                let key_start = rest.strip_prefix('.').unwrap_or(rest);
+                let end = key_start.find(['.', '[']).unwrap_or(key_start.len());
+                let key = &key_start[..end];
+                rest = &key_start[end..];
+
+                current = match current {
+                    BenValue::Dict(dict) => dict.get(key.as_bytes())?,
+                    _ => return None,
+                };
+            }
+        }
+
+        Some(current)
+    }
 }
 
-fn decode_bencoded(encoded_values: &[u8], index: usize) -> Result<(BenValue, usize), &'static str> {
-    if encoded_values[index].is_ascii_digit() {
-        decode_string(&encoded_values[index..])
-    } else if encoded_values[index] == b'i' {
-        decode_integer(&encoded_values[index..])
-    } else if encoded_values[index] == b'l' {
-        decode_list(&encoded_values[index..])
-    } else if encoded_values[index] == b'd' {
-        decode_map(&encoded_values[index..])
-    } else {
-        Err("Invalid format: expected a digit, 'i', 'l', or 'd'")
+fn render_bytes(bytes: &[u8], rendering: BytesRendering) -> String {
+    match (rendering, str::from_utf8(bytes)) {
+        (BytesRendering::Hex, Ok(string))
+            if !string.starts_with(HEX_PREFIX) && !string.starts_with(BASE64_PREFIX) =>
+        {
+            string.to_string()
+        }
+        (BytesRendering::Hex, _) => format!("{}{}", HEX_PREFIX, hex::encode(bytes)),
+        (BytesRendering::Escaped, _) => escape_bytes(bytes),
     }
 }
 
-fn to_json(values: Vec<BenValue>) -> Value {
-    let mut json_values = Vec::new();
+// `\` is doubled in every string, so `\xNN` always stands for a byte that
+// isn't UTF-8 and the text `\xff` can't be mistaken for the byte 0xFF
+pub(crate) fn escape_bytes(mut bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
 
-    for value in values {
-        match value {
-            BenValue::BenString(string) => json_values.push(Value::String(string.to_string())),
-            BenValue::BenInteger(integer) => json_values.push(Value::Number((integer).into())),
-            BenValue::BenList(list) => json_values.push(to_json(list)),
-            BenValue::BenDict(map) => json_values.push(Value::Object(map)),
+    while !bytes.is_empty() {
+        let (valid, invalid) = match str::from_utf8(bytes) {
+            Ok(string) => (string, 0),
+            Err(err) => {
+                let (valid, rest) = bytes.split_at(err.valid_up_to());
+                let valid = str::from_utf8(valid).expect("Checked to be valid UTF-8");
+                (valid, err.error_len().unwrap_or(rest.len()))
+            }
+        };
+
+        escaped.push_str(&valid.replace('\\', "\\\\"));
+        let rest = &bytes[valid.len()..];
+        for b in &rest[..invalid] {
+            escaped.push_str(&format!("\\x{:02x}", b));
         }
+        bytes = &rest[invalid..];
     }
 
-    Value::Array(json_values)
+    escaped
+}
+
+/// Error produced when decoding bencode.
+///
+/// Every variant carries the byte offset where decoding stopped and the
+/// nesting path of the value being decoded, e.g. `info.files[3].length`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BencodeError {
+    UnexpectedEof {
+        offset: usize,
+        expected: &'static str,
+        path: String,
+    },
+    UnexpectedByte {
+        offset: usize,
+        found: u8,
+        expected: &'static str,
+        path: String,
+    },
+    InvalidInteger {
+        offset: usize,
+        path: String,
+    },
+    InvalidLength {
+        offset: usize,
+        path: String,
+    },
+    NonCanonical {
+        offset: usize,
+        rule: CanonicalRule,
+        path: String,
+    },
+    LimitExceeded {
+        offset: usize,
+        limit: &'static str,
+        path: String,
+    },
+    /// Raised by serde while mapping values onto Rust types.
+    Custom {
+        offset: usize,
+        message: String,
+        path: String,
+    },
 }
 
-fn to_json_value(value: BenValue) -> Value {
-    match value {
-        BenValue::BenString(string) => Value::String(string.to_string()),
-        BenValue::BenInteger(integer) => Value::Number((integer).into()),
-        BenValue::BenList(list) => to_json(list),
-        BenValue::BenDict(map) => Value::Object(map),
+/// BEP 3 canonical-form rule broken by an input in strict mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanonicalRule {
+    LeadingZero,
+    NegativeZero,
+    UnsortedKeys,
+    DuplicateKey,
+    TrailingData,
+}
+
+impl fmt::Display for CanonicalRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rule = match self {
+            CanonicalRule::LeadingZero => "number has leading zeros",
+            CanonicalRule::NegativeZero => "integer is negative zero",
+            CanonicalRule::UnsortedKeys => "dictionary keys are not sorted",
+            CanonicalRule::DuplicateKey => "dictionary key is duplicated",
+            CanonicalRule::TrailingData => "trailing data after value",
+        };
+
+        f.write_str(rule)
     }
 }
 
-fn decode_string(encoded_values: &[u8]) -> Result<(BenValue, usize), &'static str> {
-    let mut length_str = String::new();
-    let mut iter = encoded_values.iter().enumerate();
+impl BencodeError {
+    pub fn offset(&self) -> usize {
+        match self {
+            BencodeError::UnexpectedEof { offset, .. }
+            | BencodeError::UnexpectedByte { offset, .. }
+            | BencodeError::InvalidInteger { offset, .. }
+            | BencodeError::InvalidLength { offset, .. }
+            | BencodeError::NonCanonical { offset, .. }
+            | BencodeError::LimitExceeded { offset, .. }
+            | BencodeError::Custom { offset, .. } => *offset,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        match self {
+            BencodeError::UnexpectedEof { path, .. }
+            | BencodeError::UnexpectedByte { path, .. }
+            | BencodeError::InvalidInteger { path, .. }
+            | BencodeError::InvalidLength { path, .. }
+            | BencodeError::NonCanonical { path, .. }
+            | BencodeError::LimitExceeded { path, .. }
+            | BencodeError::Custom { path, .. } => path,
+        }
+    }
+
+    pub(crate) fn custom(message: impl fmt::Display) -> BencodeError {
+        BencodeError::Custom {
+            offset: 0,
+            message: message.to_string(),
+            path: String::new(),
+        }
+    }
+
+    // Prefixes the path of an error raised while deserializing a nested
+    // value. The innermost value to see the error also records its offset.
+    pub(crate) fn nested(mut self, segment: PathSegment, value_offset: usize) -> BencodeError {
+        if let BencodeError::Custom { offset, path, .. } = &mut self {
+            if path.is_empty() {
+                *offset = value_offset;
+            }
+
+            let mut prefixed = format_path(&[segment]);
+            if !path.is_empty() && !path.starts_with('[') {
+                prefixed.push('.');
+            }
+            prefixed.push_str(path);
+            *path = prefixed;
+        }
 
-    // Extract the length of the string
-    while let Some((index, &b)) = iter.next() {
-        if b.is_ascii_digit() {
-            length_str.push(b as char);
-        } else if b == b':' {
-            let length = usize::from_str(&length_str).map_err(|_| "Invalid length")?;
-            let string_start = index + 1;
-            let string_end = string_start + length;
+        self
+    }
+}
 
-            if string_end > encoded_values.len() {
-                return Err("Invalid length");
+impl fmt::Display for BencodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BencodeError::UnexpectedEof { expected, .. } => {
+                write!(f, "unexpected end of input, expected {}", expected)?
             }
+            BencodeError::UnexpectedByte {
+                found, expected, ..
+            } => write!(
+                f,
+                "unexpected byte {:?}, expected {}",
+                *found as char, expected
+            )?,
+            BencodeError::InvalidInteger { .. } => write!(f, "invalid integer")?,
+            BencodeError::InvalidLength { .. } => write!(f, "invalid string length")?,
+            BencodeError::LimitExceeded { limit, .. } => write!(f, "{} limit exceeded", limit)?,
+            BencodeError::Custom { message, .. } => write!(f, "{}", message)?,
+            BencodeError::NonCanonical { rule, .. } => {
+                write!(f, "non-canonical bencode: {}", rule)?
+            }
+        }
 
-            let string = String::from_utf8_lossy(&encoded_values[string_start..string_end]);
-            return Ok((BenValue::BenString(string.to_string()), string_end));
-        } else {
-            return Err("Invalid format");
+        write!(f, " at offset {}", self.offset())?;
+        if !self.path().is_empty() {
+            write!(f, " ({})", self.path())?;
         }
+
+        Ok(())
+    }
+}
+
+impl Error for BencodeError {}
+
+impl From<BencodeError> for io::Error {
+    fn from(err: BencodeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+pub fn decode_bencoded_values(encoded_values: &[u8]) -> Result<Vec<BenValue>, BencodeError> {
+    let mut decoder = Decoder::new(encoded_values, false);
+    let mut values = Vec::new();
+
+    while decoder.pos < encoded_values.len() {
+        values.push(decoder.decode_bencoded()?.to_value());
+    }
+
+    Ok(values)
+}
+
+/// Decodes the first value of the input without copying it.
+///
+/// Anything after the value is left alone; its `span.end` tells where the
+/// value stopped.
+pub fn decode_bencoded_ref(encoded_value: &[u8]) -> Result<BenRef<'_>, BencodeError> {
+    Decoder::new(encoded_value, false).decode_bencoded()
+}
+
+// `decode_bencoded_ref` with the nesting limit of `limits`
+pub(crate) fn decode_bencoded_ref_within(
+    encoded_value: &[u8],
+    limits: DecodeLimits,
+) -> Result<BenRef<'_>, BencodeError> {
+    let mut decoder = Decoder::new(encoded_value, false);
+    decoder.max_depth = limits.max_depth;
+    decoder.decode_bencoded()
+}
+
+/// Decodes exactly one value, rejecting anything that is not in BEP 3
+/// canonical form: leading zeros, negative zero, unsorted or duplicate
+/// dictionary keys and trailing data.
+///
+/// Only canonical input re-encodes to the same bytes, so this is what to use
+/// when the info hash of a torrent has to stay stable.
+pub fn decode_bencoded_strict(encoded_value: &[u8]) -> Result<BenValue, BencodeError> {
+    let mut decoder = Decoder::new(encoded_value, true);
+    let value = decoder.decode_bencoded()?;
+
+    if decoder.pos < encoded_value.len() {
+        return Err(decoder.non_canonical(decoder.pos, CanonicalRule::TrailingData));
     }
 
-    Err("Invalid format")
+    Ok(value.to_value())
 }
 
-fn decode_integer(encoded_values: &[u8]) -> Result<(BenValue, usize), &'static str> {
-    let mut value_str = String::new();
-    let mut iter = encoded_values.iter().enumerate();
-    iter.next();
+// keys are only rendered when an error needs the path
+pub(crate) enum PathSegment<'a> {
+    Key(&'a [u8]),
+    Index(usize),
+}
+
+// renders a nesting path as e.g. `info.files[3].length`
+pub(crate) fn format_path(segments: &[PathSegment]) -> String {
+    let mut path = String::new();
 
-    // Extract the length of the string
-    while let Some((index, &b)) = iter.next() {
-        if b.is_ascii_digit() || b == b'-' {
-            value_str.push(b as char);
-        } else if b == b'e' {
-            let value = i64::from_str(&value_str).map_err(|_| "Invalid value")?;
-            return Ok((BenValue::BenInteger(value), index + 1));
-        } else {
-            return Err("Invalid format");
+    for segment in segments {
+        match segment {
+            PathSegment::Key(key) => {
+                if !path.is_empty() {
+                    path.push('.');
+                }
+                path.push_str(&escape_bytes(key));
+            }
+            PathSegment::Index(index) => path.push_str(&format!("[{}]", index)),
         }
     }
 
-    Err("Invalid format")
+    path
 }
 
-fn decode_list(encoded_values: &[u8]) -> Result<(BenValue, usize), &'static str> {
-    let mut values: Vec<BenValue> = Vec::new();
-    let mut current_index: usize = 1; // 1 to remove l
+struct Decoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+    path: Vec<PathSegment<'a>>,
+    strict: bool,
+    // lists and dictionaries are decoded recursively, so deep nesting would
+    // overflow the stack
+    max_depth: usize,
+}
 
-    if encoded_values[current_index] == b'e' {
-        return Ok((BenValue::BenList(values), current_index + 1));
+impl<'a> Decoder<'a> {
+    fn new(input: &'a [u8], strict: bool) -> Decoder<'a> {
+        Decoder {
+            input,
+            pos: 0,
+            path: Vec::new(),
+            strict,
+            max_depth: DecodeLimits::default().max_depth,
+        }
     }
 
-    loop {
-        let decoded = decode_bencoded(&encoded_values, current_index);
+    fn path_string(&self) -> String {
+        format_path(&self.path)
+    }
 
-        if decoded.is_err() {
-            let err = &decoded.err();
-            panic!("Error parsing list: {}", err.unwrap());
-        } else {
-            let (value, size) = decoded.unwrap();
+    fn peek(&self, expected: &'static str) -> Result<u8, BencodeError> {
+        match self.input.get(self.pos) {
+            Some(&b) => Ok(b),
+            None => Err(BencodeError::UnexpectedEof {
+                offset: self.pos,
+                expected,
+                path: self.path_string(),
+            }),
+        }
+    }
 
-            values.push(value);
-            current_index += size;
+    fn unexpected(&self, found: u8, expected: &'static str) -> BencodeError {
+        BencodeError::UnexpectedByte {
+            offset: self.pos,
+            found,
+            expected,
+            path: self.path_string(),
+        }
+    }
+
+    fn non_canonical(&self, offset: usize, rule: CanonicalRule) -> BencodeError {
+        BencodeError::NonCanonical {
+            offset,
+            rule,
+            path: self.path_string(),
         }
+    }
 
-        if encoded_values[current_index] == b'e' {
-            break;
+    fn enter_container(&mut self) -> Result<(), BencodeError> {
+        if self.path.len() >= self.max_depth {
+            return Err(BencodeError::LimitExceeded {
+                offset: self.pos,
+                limit: "depth",
+                path: self.path_string(),
+            });
         }
+
+        self.pos += 1;
+        Ok(())
     }
 
-    Ok((BenValue::BenList(values), current_index + 1))
-}
+    fn decode_bencoded(&mut self) -> Result<BenRef<'a>, BencodeError> {
+        let start = self.pos;
+
+        let kind = match self.peek("a digit, 'i', 'l', or 'd'")? {
+            b'0'..=b'9' => BenRefKind::Bytes(self.decode_string()?),
+            b'i' => BenRefKind::Int(self.decode_integer()?),
+            b'l' => self.decode_list()?,
+            b'd' => self.decode_map()?,
+            b => return Err(self.unexpected(b, "a digit, 'i', 'l', or 'd'")),
+        };
+
+        Ok(BenRef::new(kind, self.input, start..self.pos))
+    }
+
+    fn decode_string(&mut self) -> Result<&'a [u8], BencodeError> {
+        let start = self.pos;
+
+        // Extract the length of the string
+        loop {
+            match self.peek("a digit or ':'")? {
+                b'0'..=b'9' => self.pos += 1,
+                b':' => break,
+                b => return Err(self.unexpected(b, "a digit or ':'")),
+            }
+        }
+
+        let length_str = str::from_utf8(&self.input[start..self.pos]).unwrap_or_default();
+        if self.strict && length_str.len() > 1 && length_str.starts_with('0') {
+            return Err(self.non_canonical(start, CanonicalRule::LeadingZero));
+        }
+        let length = usize::from_str(length_str).map_err(|_| BencodeError::InvalidLength {
+            offset: start,
+            path: self.path_string(),
+        })?;
+        self.pos += 1;
 
-fn decode_map(encoded_values: &[u8]) -> Result<(BenValue, usize), &'static str> {
-    let mut result_map: Map<String, Value> = Map::new();
-    let mut current_index: usize = 1; // 1 to remove d
+        let string_end = match self.pos.checked_add(length) {
+            Some(end) if end <= self.input.len() => end,
+            _ => {
+                return Err(BencodeError::UnexpectedEof {
+                    offset: self.input.len(),
+                    expected: "string data",
+                    path: self.path_string(),
+                })
+            }
+        };
+
+        let bytes = &self.input[self.pos..string_end];
+        self.pos = string_end;
 
-    if encoded_values[current_index] == b'e' {
-        return Ok((BenValue::BenDict(result_map), current_index + 1));
+        Ok(bytes)
     }
 
-    loop {
-        let (key, size): (BenValue, usize) =
-            decode_string(&encoded_values[current_index..]).unwrap();
-        current_index += size;
+    fn decode_integer(&mut self) -> Result<i64, BencodeError> {
+        let start = self.pos;
+        self.pos += 1; // skip i
 
-        let (value, size): (BenValue, usize) =
-            decode_bencoded(&encoded_values, current_index).unwrap();
-        current_index += size;
+        if self.peek("a digit or '-'")? == b'-' {
+            self.pos += 1;
+        }
 
-        match key {
-            BenValue::BenString(string) => {
-                result_map.insert(string, to_json_value(value));
+        loop {
+            match self.peek("a digit or 'e'")? {
+                b'0'..=b'9' => self.pos += 1,
+                b'e' => break,
+                b => return Err(self.unexpected(b, "a digit or 'e'")),
+            }
+        }
+
+        let value_str = str::from_utf8(&self.input[start + 1..self.pos]).unwrap_or_default();
+        if self.strict {
+            let digits = value_str.trim_start_matches('-');
+            if digits.len() > 1 && digits.starts_with('0') {
+                return Err(self.non_canonical(start, CanonicalRule::LeadingZero));
+            }
+            if value_str == "-0" {
+                return Err(self.non_canonical(start, CanonicalRule::NegativeZero));
             }
-            _ => panic!("Invalid key type"),
         }
+        let value = i64::from_str(value_str).map_err(|_| BencodeError::InvalidInteger {
+            offset: start,
+            path: self.path_string(),
+        })?;
+        self.pos += 1;
+
+        Ok(value)
+    }
+
+    fn decode_list(&mut self) -> Result<BenRefKind<'a>, BencodeError> {
+        let mut values: Vec<BenRef<'a>> = Vec::new();
+        self.enter_container()?; // skip l
+
+        while self.peek("a list item or 'e'")? != b'e' {
+            self.path.push(PathSegment::Index(values.len()));
+            let value = self.decode_bencoded()?;
+            self.path.pop();
+
+            values.push(value);
+        }
+        self.pos += 1;
 
-        if encoded_values[current_index] == b'e' {
-            break;
+        Ok(BenRefKind::List(values))
+    }
+
+    fn decode_map(&mut self) -> Result<BenRefKind<'a>, BencodeError> {
+        let mut entries: Vec<(&'a [u8], BenRef<'a>)> = Vec::new();
+        self.enter_container()?; // skip d
+
+        loop {
+            match self.peek("a dictionary key or 'e'")? {
+                b'e' => break,
+                b'0'..=b'9' => {}
+                b => return Err(self.unexpected(b, "a dictionary key or 'e'")),
+            }
+
+            let key_start = self.pos;
+            let key = self.decode_string()?;
+
+            if self.strict {
+                if let Some((last_key, _)) = entries.last() {
+                    if key == *last_key {
+                        return Err(self.non_canonical(key_start, CanonicalRule::DuplicateKey));
+                    }
+                    if key < *last_key {
+                        return Err(self.non_canonical(key_start, CanonicalRule::UnsortedKeys));
+                    }
+                }
+            }
+
+            self.path.push(PathSegment::Key(key));
+            let value = self.decode_bencoded()?;
+            self.path.pop();
+
+            entries.push((key, value));
         }
+        self.pos += 1;
+
+        Ok(BenRefKind::Dict(entries))
     }
+}
 
-    Ok((BenValue::BenDict(result_map), current_index + 1))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nesting_is_capped_at_max_depth() {
+        let depth = DecodeLimits::default().max_depth;
+        let nested = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+
+        assert!(decode_bencoded_values(&nested(depth)).is_ok());
+        assert!(matches!(
+            decode_bencoded_values(&nested(depth + 1)),
+            Err(BencodeError::LimitExceeded { limit: "depth", .. })
+        ));
+        assert!(matches!(
+            decode_bencoded_strict(&b"d1:a".repeat(200_000)),
+            Err(BencodeError::LimitExceeded { .. })
+        ));
+        assert!(matches!(
+            decode_bencoded_ref(&vec![b'l'; 200_000]),
+            Err(BencodeError::LimitExceeded { .. })
+        ));
+    }
 }
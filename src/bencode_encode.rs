@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+use crate::bencode_decode::{format_path, BenValue, PathSegment, BASE64_PREFIX, HEX_PREFIX};
+
+/// Encodes a value as canonical bencode.
+///
+/// Dictionary keys come out sorted (they are held in a `BTreeMap`) and
+/// integers use their minimal decimal form, so decoding canonical input and
+/// encoding it again yields the original bytes.
+pub fn encode_bencoded_value(value: &BenValue) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    encode_into(value, &mut encoded);
+
+    encoded
+}
+
+pub fn encode_bencoded_values(values: &[BenValue]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+
+    for value in values {
+        encode_into(value, &mut encoded);
+    }
+
+    encoded
+}
+
+pub fn encode_into(value: &BenValue, out: &mut Vec<u8>) {
+    match value {
+        BenValue::Bytes(bytes) => encode_bytes(bytes, out),
+        BenValue::Int(integer) => {
+            out.push(b'i');
+            out.extend(integer.to_string().as_bytes());
+            out.push(b'e');
+        }
+        BenValue::List(list) => {
+            out.push(b'l');
+            for item in list {
+                encode_into(item, out);
+            }
+            out.push(b'e');
+        }
+        BenValue::Dict(dict) => {
+            out.push(b'd');
+            for (key, item) in dict {
+                encode_bytes(key, out);
+                encode_into(item, out);
+            }
+            out.push(b'e');
+        }
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend(bytes);
+}
+
+/// Builds a value from JSON, the inverse of `BenValue::to_json_with`.
+///
+/// Strings starting with `hex:` or `base64:` are decoded into the bytes they
+/// spell, other strings are taken as UTF-8. JSON has no bencode form for
+/// `null`, booleans or fractional numbers, so those are rejected.
+pub fn bencode_from_json(json: &Value) -> Result<BenValue, String> {
+    from_json_at(json, &mut Vec::new())
+}
+
+fn from_json_at<'a>(json: &'a Value, path: &mut Vec<PathSegment<'a>>) -> Result<BenValue, String> {
+    let unsupported =
+        |kind: &str, path: &[PathSegment]| located(format!("{} has no bencode form", kind), path);
+
+    match json {
+        Value::Null => Err(unsupported("null", path)),
+        Value::Bool(_) => Err(unsupported("boolean", path)),
+        Value::Number(number) => number
+            .as_i64()
+            .map(BenValue::Int)
+            .ok_or_else(|| unsupported("non-integer number", path)),
+        Value::String(string) => bytes_from_json(string)
+            .map(BenValue::Bytes)
+            .map_err(|err| located(err, path)),
+        Value::Array(array) => {
+            let mut list = Vec::with_capacity(array.len());
+            for (index, item) in array.iter().enumerate() {
+                path.push(PathSegment::Index(index));
+                list.push(from_json_at(item, path)?);
+                path.pop();
+            }
+
+            Ok(BenValue::List(list))
+        }
+        Value::Object(object) => {
+            let mut dict = BTreeMap::new();
+            for (key, item) in object {
+                path.push(PathSegment::Key(key.as_bytes()));
+                let key_bytes = bytes_from_json(key).map_err(|err| located(err, path))?;
+
+                dict.insert(key_bytes, from_json_at(item, path)?);
+                path.pop();
+            }
+
+            Ok(BenValue::Dict(dict))
+        }
+    }
+}
+
+fn located(message: String, path: &[PathSegment]) -> String {
+    match format_path(path) {
+        at if at.is_empty() => message,
+        at => format!("{} (at {})", message, at),
+    }
+}
+
+fn bytes_from_json(string: &str) -> Result<Vec<u8>, String> {
+    if let Some(encoded) = string.strip_prefix(HEX_PREFIX) {
+        hex::decode(encoded).map_err(|err| format!("invalid hex string: {}", err))
+    } else if let Some(encoded) = string.strip_prefix(BASE64_PREFIX) {
+        decode_base64(encoded).ok_or_else(|| "invalid base64 string".to_string())
+    } else {
+        Ok(string.as_bytes().to_vec())
+    }
+}
+
+// standard alphabet, padding optional
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.trim_end_matches('=').as_bytes();
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for &c in encoded {
+        let sextet = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+
+        buffer = (buffer << 6) | sextet as u32;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+
+    // a single leftover sextet can't encode a whole byte
+    if bits >= 6 {
+        return None;
+    }
+
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode_decode::{decode_bencoded_strict, decode_bencoded_values};
+
+    fn round_trip(encoded: &[u8]) -> Vec<u8> {
+        let values = decode_bencoded_values(encoded).expect("Failed to decode");
+        encode_bencoded_values(&values)
+    }
+
+    #[test]
+    fn sample_torrent_round_trips() {
+        let torrent = include_bytes!("../sample.torrent");
+
+        let value = decode_bencoded_strict(torrent).expect("sample.torrent is canonical");
+        assert_eq!(encode_bencoded_value(&value), torrent);
+    }
+
+    #[test]
+    fn canonical_inputs_round_trip() {
+        let inputs: [&[u8]; 10] = [
+            b"0:",
+            b"5:hello",
+            b"3:\x00\xff\\",
+            b"i0e",
+            b"i-42e",
+            b"i9223372036854775807e",
+            b"le",
+            b"l4:spami7el1:aee",
+            b"de",
+            b"d3:bar4:spam3:fooi42e4:infod6:lengthi5eee",
+        ];
+
+        for input in inputs {
+            assert_eq!(
+                round_trip(input),
+                input,
+                "{:?}",
+                String::from_utf8_lossy(input)
+            );
+            assert!(decode_bencoded_strict(input).is_ok());
+        }
+    }
+
+    #[test]
+    fn several_values_round_trip() {
+        assert_eq!(round_trip(b"i1e5:helloli2ee"), b"i1e5:helloli2ee");
+    }
+
+    #[test]
+    fn non_canonical_inputs_encode_canonically() {
+        let cases: [(&[u8], &[u8]); 3] = [
+            (b"d1:bi1e1:ai2ee", b"d1:ai2e1:bi1ee"),
+            (b"d1:ai1e1:ai2ee", b"d1:ai2ee"),
+            (b"ld1:z0:1:a0:ee", b"ld1:a0:1:z0:ee"),
+        ];
+
+        for (input, canonical) in cases {
+            assert!(decode_bencoded_strict(input).is_err());
+            assert_eq!(round_trip(input), canonical);
+            assert_eq!(round_trip(canonical), canonical);
+        }
+    }
+
+    #[test]
+    fn json_round_trips_through_bencode() {
+        let value = BenValue::Dict(BTreeMap::from([
+            (b"bytes".to_vec(), BenValue::Bytes(vec![0xff, 0x00])),
+            (b"int".to_vec(), BenValue::Int(-3)),
+            (b"text".to_vec(), BenValue::Bytes(b"hex:not hex".to_vec())),
+        ]));
+
+        let json = value.to_json_with(crate::bencode_decode::BytesRendering::Hex);
+        assert_eq!(bencode_from_json(&json), Ok(value));
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use crate::bencode_decode::BenValue;
+
+/// A bencode value borrowed from its input buffer.
+///
+/// Byte strings are slices into the input, and every value remembers the
+/// exact byte span it was decoded from, so the original encoding of any
+/// sub-value (e.g. the `info` dictionary) can be recovered without copying.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BenRef<'a> {
+    pub kind: BenRefKind<'a>,
+    pub span: Range<usize>,
+    raw: &'a [u8],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BenRefKind<'a> {
+    Bytes(&'a [u8]),
+    Int(i64),
+    List(Vec<BenRef<'a>>),
+    /// Entries in input order; duplicates are kept as they appear.
+    Dict(Vec<(&'a [u8], BenRef<'a>)>),
+}
+
+impl<'a> BenRef<'a> {
+    pub(crate) fn new(kind: BenRefKind<'a>, input: &'a [u8], span: Range<usize>) -> BenRef<'a> {
+        BenRef {
+            kind,
+            raw: &input[span.clone()],
+            span,
+        }
+    }
+
+    /// The original encoded bytes of this value.
+    pub fn raw(&self) -> &'a [u8] {
+        self.raw
+    }
+
+    /// Looks up a dictionary entry. When a key is duplicated the last
+    /// occurrence wins, matching how `BenValue` is built.
+    pub fn get(&self, key: &[u8]) -> Option<&BenRef<'a>> {
+        match &self.kind {
+            BenRefKind::Dict(entries) => entries
+                .iter()
+                .rev()
+                .find(|(entry_key, _)| *entry_key == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self.kind {
+            BenRefKind::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self.kind {
+            BenRefKind::Int(integer) => Some(integer),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[BenRef<'a>]> {
+        match &self.kind {
+            BenRefKind::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn to_value(&self) -> BenValue {
+        match &self.kind {
+            BenRefKind::Bytes(bytes) => BenValue::Bytes(bytes.to_vec()),
+            BenRefKind::Int(integer) => BenValue::Int(*integer),
+            BenRefKind::List(list) => BenValue::List(list.iter().map(BenRef::to_value).collect()),
+            BenRefKind::Dict(entries) => {
+                let mut dict = BTreeMap::new();
+                for (key, value) in entries {
+                    dict.insert(key.to_vec(), value.to_value());
+                }
+                BenValue::Dict(dict)
+            }
+        }
+    }
+}
+
+pub(crate) const RAW_BENCODE_TOKEN: &str = "$bencode::RawBencode";
+
+/// The original encoding of a sub-value, captured as-is when deserializing.
+///
+/// Serializing decodes it and encodes it again, so the output only matches
+/// the original bytes when they are canonical; anything else (unsorted or
+/// duplicate keys, trailing data, ...) is rejected rather than silently
+/// rewritten.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawBencode(pub Vec<u8>);
@@ -0,0 +1,431 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::ser::{self, Serialize};
+
+use crate::bencode_decode::{decode_bencoded_strict, BenValue, BencodeError};
+use crate::bencode_encode::encode_bencoded_value;
+use crate::bencode_ref::{RawBencode, RAW_BENCODE_TOKEN};
+
+impl ser::Error for BencodeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        BencodeError::custom(msg)
+    }
+}
+
+/// Serializes `value` as canonical bencode.
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, BencodeError> {
+    Ok(encode_bencoded_value(&to_value(value)?))
+}
+
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<BenValue, BencodeError> {
+    value.serialize(Serializer).and_then(|value| {
+        value.ok_or_else(|| BencodeError::custom("cannot encode a missing value"))
+    })
+}
+
+/// Serializes Rust values into a `BenValue` tree.
+///
+/// `None` and `()` have no bencode representation; they come out as `None`
+/// so that struct fields and map entries holding them can be left out.
+pub struct Serializer;
+
+fn missing_in_list() -> BencodeError {
+    BencodeError::custom("cannot encode a missing value inside a list")
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = Option<BenValue>;
+    type Error = BencodeError;
+
+    type SerializeSeq = ListSerializer;
+    type SerializeTuple = ListSerializer;
+    type SerializeTupleStruct = ListSerializer;
+    type SerializeTupleVariant = VariantSerializer<ListSerializer>;
+    type SerializeMap = DictSerializer;
+    type SerializeStruct = DictSerializer;
+    type SerializeStructVariant = VariantSerializer<DictSerializer>;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, BencodeError> {
+        Ok(Some(BenValue::Int(v as i64)))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, BencodeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, BencodeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, BencodeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, BencodeError> {
+        Ok(Some(BenValue::Int(v)))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, BencodeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, BencodeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, BencodeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, BencodeError> {
+        let v = i64::try_from(v).map_err(|_| BencodeError::custom("integer out of range"))?;
+        self.serialize_i64(v)
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<Self::Ok, BencodeError> {
+        Err(BencodeError::custom(
+            "bencode has no floating point numbers",
+        ))
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<Self::Ok, BencodeError> {
+        Err(BencodeError::custom(
+            "bencode has no floating point numbers",
+        ))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, BencodeError> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, BencodeError> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, BencodeError> {
+        Ok(Some(BenValue::Bytes(v.to_vec())))
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, BencodeError> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, BencodeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, BencodeError> {
+        Ok(None)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, BencodeError> {
+        Ok(None)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, BencodeError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, BencodeError> {
+        if name == RAW_BENCODE_TOKEN {
+            return match value.serialize(self)? {
+                Some(BenValue::Bytes(raw)) => Ok(Some(decode_bencoded_strict(&raw)?)),
+                _ => Err(BencodeError::custom("raw bencode must be a byte string")),
+            };
+        }
+
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, BencodeError> {
+        let mut dict = BTreeMap::new();
+        if let Some(value) = value.serialize(Serializer)? {
+            dict.insert(variant.as_bytes().to_vec(), value);
+        }
+
+        Ok(Some(BenValue::Dict(dict)))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, BencodeError> {
+        Ok(ListSerializer {
+            list: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, BencodeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, BencodeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, BencodeError> {
+        Ok(VariantSerializer {
+            variant,
+            inner: self.serialize_seq(Some(len))?,
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, BencodeError> {
+        Ok(DictSerializer {
+            dict: BTreeMap::new(),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, BencodeError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant, BencodeError> {
+        Ok(VariantSerializer {
+            variant,
+            inner: self.serialize_map(Some(len))?,
+        })
+    }
+}
+
+pub struct ListSerializer {
+    list: Vec<BenValue>,
+}
+
+impl ser::SerializeSeq for ListSerializer {
+    type Ok = Option<BenValue>;
+    type Error = BencodeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        let value = value.serialize(Serializer)?.ok_or_else(missing_in_list)?;
+        self.list.push(value);
+
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, BencodeError> {
+        Ok(Some(BenValue::List(self.list)))
+    }
+}
+
+impl ser::SerializeTuple for ListSerializer {
+    type Ok = Option<BenValue>;
+    type Error = BencodeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, BencodeError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for ListSerializer {
+    type Ok = Option<BenValue>;
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, BencodeError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+pub struct DictSerializer {
+    dict: BTreeMap<Vec<u8>, BenValue>,
+    key: Option<Vec<u8>>,
+}
+
+impl ser::SerializeMap for DictSerializer {
+    type Ok = Option<BenValue>;
+    type Error = BencodeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), BencodeError> {
+        match key.serialize(Serializer)? {
+            Some(BenValue::Bytes(key)) => {
+                self.key = Some(key);
+                Ok(())
+            }
+            _ => Err(BencodeError::custom("dictionary keys must be byte strings")),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| BencodeError::custom("value serialized before key"))?;
+
+        if let Some(value) = value.serialize(Serializer)? {
+            self.dict.insert(key, value);
+        }
+
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, BencodeError> {
+        Ok(Some(BenValue::Dict(self.dict)))
+    }
+}
+
+impl ser::SerializeStruct for DictSerializer {
+    type Ok = Option<BenValue>;
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), BencodeError> {
+        if let Some(value) = value.serialize(Serializer)? {
+            self.dict.insert(key.as_bytes().to_vec(), value);
+        }
+
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, BencodeError> {
+        ser::SerializeMap::end(self)
+    }
+}
+
+/// Wraps a tuple or struct variant in a single-key dictionary named after
+/// the variant.
+pub struct VariantSerializer<S> {
+    variant: &'static str,
+    inner: S,
+}
+
+impl<S> VariantSerializer<S> {
+    fn wrap(variant: &'static str, value: Option<BenValue>) -> Option<BenValue> {
+        let mut dict = BTreeMap::new();
+        if let Some(value) = value {
+            dict.insert(variant.as_bytes().to_vec(), value);
+        }
+
+        Some(BenValue::Dict(dict))
+    }
+}
+
+impl ser::SerializeTupleVariant for VariantSerializer<ListSerializer> {
+    type Ok = Option<BenValue>;
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        ser::SerializeSeq::serialize_element(&mut self.inner, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, BencodeError> {
+        let value = ser::SerializeSeq::end(self.inner)?;
+        Ok(Self::wrap(self.variant, value))
+    }
+}
+
+impl ser::SerializeStructVariant for VariantSerializer<DictSerializer> {
+    type Ok = Option<BenValue>;
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), BencodeError> {
+        ser::SerializeStruct::serialize_field(&mut self.inner, key, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, BencodeError> {
+        let value = ser::SerializeMap::end(self.inner)?;
+        Ok(Self::wrap(self.variant, value))
+    }
+}
+
+impl Serialize for BenValue {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use ser::{SerializeMap, SerializeSeq};
+
+        match self {
+            BenValue::Bytes(bytes) => serializer.serialize_bytes(bytes),
+            BenValue::Int(integer) => serializer.serialize_i64(*integer),
+            BenValue::List(list) => {
+                let mut seq = serializer.serialize_seq(Some(list.len()))?;
+                for item in list {
+                    seq.serialize_element(item)?;
+                }
+                seq.end()
+            }
+            BenValue::Dict(dict) => {
+                let mut map = serializer.serialize_map(Some(dict.len()))?;
+                for (key, value) in dict {
+                    map.serialize_entry(serde_bytes::Bytes::new(key), value)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+impl Serialize for RawBencode {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_struct(RAW_BENCODE_TOKEN, serde_bytes::Bytes::new(&self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_bencode_is_written_back_unchanged() {
+        let raw = RawBencode(b"d1:ai1e1:bl1:xee".to_vec());
+        assert_eq!(to_bytes(&raw).unwrap(), raw.0);
+    }
+
+    #[test]
+    fn non_canonical_raw_bencode_is_rejected() {
+        for raw in [
+            &b"d1:bi1e1:ai2ee"[..],
+            b"d1:ai1e1:ai2ee",
+            b"i1ei2e",
+            b"i01e",
+        ] {
+            assert!(to_bytes(&RawBencode(raw.to_vec())).is_err(), "{raw:?}");
+        }
+    }
+}
@@ -0,0 +1,409 @@
+use crate::bencode_decode::{
+    decode_bencoded_ref_within, format_path, BenValue, BencodeError, PathSegment,
+};
+
+// longest decimal i64 / usize is 20 characters including the sign
+const MAX_NUMBER_DIGITS: usize = 20;
+
+/// Limits enforced on bencode coming from untrusted peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    pub max_depth: usize,
+    pub max_size: usize,
+    pub max_string_len: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_depth: 64,
+            max_size: 8 * 1024 * 1024,
+            max_string_len: 8 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Decoded {
+    NeedMore,
+    Value(BenValue),
+}
+
+enum Frame {
+    List { index: usize },
+    // the key being decoded, as its span in the buffer
+    Dict { key: Option<(usize, usize)> },
+}
+
+/// Push-style decoder for bencode arriving in chunks.
+///
+/// Bytes are fed with `push` and `next_value` returns `Decoded::NeedMore`
+/// until a whole top-level value is buffered. Input is validated as it
+/// arrives, so a value that breaks the limits is rejected before the rest
+/// of it has to be received.
+pub struct StreamDecoder {
+    limits: DecodeLimits,
+    buffer: Vec<u8>,
+    consumed: usize,
+    pos: usize,
+    stack: Vec<Frame>,
+}
+
+impl StreamDecoder {
+    pub fn new(limits: DecodeLimits) -> StreamDecoder {
+        StreamDecoder {
+            limits,
+            buffer: Vec::new(),
+            consumed: 0,
+            pos: 0,
+            stack: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Bytes received but not yet returned as part of a value, e.g. the raw
+    /// piece data following the dictionary of a ut_metadata message.
+    pub fn remaining(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    pub fn next_value(&mut self) -> Result<Decoded, BencodeError> {
+        while self.pos < self.buffer.len() {
+            if !self.scan_token()? {
+                return Ok(Decoded::NeedMore);
+            }
+
+            if self.stack.is_empty() {
+                let value =
+                    decode_bencoded_ref_within(&self.buffer[..self.pos], self.limits)?.to_value();
+
+                self.buffer.drain(..self.pos);
+                self.consumed += self.pos;
+                self.pos = 0;
+
+                return Ok(Decoded::Value(value));
+            }
+        }
+
+        Ok(Decoded::NeedMore)
+    }
+
+    // consumes one complete token at `pos`, or returns false if more input
+    // is needed to finish it
+    fn scan_token(&mut self) -> Result<bool, BencodeError> {
+        let b = self.buffer[self.pos];
+
+        if let Some(Frame::Dict { key: None }) = self.stack.last() {
+            if b != b'e' && !b.is_ascii_digit() {
+                return Err(self.unexpected(b, "a dictionary key or 'e'"));
+            }
+        }
+
+        match b {
+            b'e' => {
+                match self.stack.last() {
+                    None => return Err(self.unexpected(b, "a digit, 'i', 'l', or 'd'")),
+                    Some(Frame::Dict { key: Some(_) }) => {
+                        return Err(self.unexpected(b, "a dictionary value"))
+                    }
+                    Some(_) => {}
+                }
+
+                self.stack.pop();
+                self.pos += 1;
+                self.finish_item();
+            }
+            b'l' | b'd' => {
+                if self.stack.len() >= self.limits.max_depth {
+                    return Err(self.limit_exceeded("depth"));
+                }
+
+                self.stack.push(if b == b'l' {
+                    Frame::List { index: 0 }
+                } else {
+                    Frame::Dict { key: None }
+                });
+                self.pos += 1;
+            }
+            b'i' => {
+                let Some(end) = self.scan_integer()? else {
+                    return Ok(false);
+                };
+
+                self.pos = end;
+                self.finish_item();
+            }
+            b'0'..=b'9' => {
+                let Some((data_start, length)) = self.scan_string_header()? else {
+                    return Ok(false);
+                };
+
+                if self.buffer.len() < data_start + length {
+                    return Ok(false);
+                }
+
+                self.pos = data_start + length;
+
+                if let Some(Frame::Dict { key: key @ None }) = self.stack.last_mut() {
+                    *key = Some((data_start, data_start + length));
+                } else {
+                    self.finish_item();
+                }
+            }
+            b => return Err(self.unexpected(b, "a digit, 'i', 'l', or 'd'")),
+        }
+
+        if self.pos > self.limits.max_size {
+            return Err(self.limit_exceeded("size"));
+        }
+
+        Ok(true)
+    }
+
+    // returns the index just past the closing e
+    fn scan_integer(&self) -> Result<Option<usize>, BencodeError> {
+        let start = self.pos + 1;
+
+        for (index, &b) in self.buffer.iter().enumerate().skip(start) {
+            match b {
+                b'e' => {
+                    let digits = std::str::from_utf8(&self.buffer[start..index]).unwrap();
+                    return match digits.parse::<i64>() {
+                        Ok(_) => Ok(Some(index + 1)),
+                        Err(_) => Err(BencodeError::InvalidInteger {
+                            offset: self.consumed + self.pos,
+                            path: self.path_string(),
+                        }),
+                    };
+                }
+                b'-' if index == start => {}
+                b'0'..=b'9' => {}
+                b => return Err(self.unexpected_at(index, b, "a digit or 'e'")),
+            }
+
+            if index - start >= MAX_NUMBER_DIGITS {
+                return Err(BencodeError::InvalidInteger {
+                    offset: self.consumed + self.pos,
+                    path: self.path_string(),
+                });
+            }
+        }
+
+        Ok(None)
+    }
+
+    // returns where the string data starts and its declared length
+    fn scan_string_header(&self) -> Result<Option<(usize, usize)>, BencodeError> {
+        for (index, &b) in self.buffer.iter().enumerate().skip(self.pos) {
+            match b {
+                b':' => {
+                    let digits = std::str::from_utf8(&self.buffer[self.pos..index]).unwrap();
+                    let length = digits.parse::<usize>().map_err(|_| self.invalid_length())?;
+
+                    if length > self.limits.max_string_len {
+                        return Err(self.limit_exceeded("string length"));
+                    }
+                    if index + 1 + length > self.limits.max_size {
+                        return Err(self.limit_exceeded("size"));
+                    }
+
+                    return Ok(Some((index + 1, length)));
+                }
+                b'0'..=b'9' => {}
+                b => return Err(self.unexpected_at(index, b, "a digit or ':'")),
+            }
+
+            if index - self.pos >= MAX_NUMBER_DIGITS {
+                return Err(self.invalid_length());
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn finish_item(&mut self) {
+        match self.stack.last_mut() {
+            Some(Frame::List { index }) => *index += 1,
+            Some(Frame::Dict { key }) => *key = None,
+            None => {}
+        }
+    }
+
+    fn path_string(&self) -> String {
+        let segments: Vec<PathSegment> = self
+            .stack
+            .iter()
+            .filter_map(|frame| match frame {
+                Frame::List { index } => Some(PathSegment::Index(*index)),
+                Frame::Dict { key } => {
+                    key.map(|(start, end)| PathSegment::Key(&self.buffer[start..end]))
+                }
+            })
+            .collect();
+
+        format_path(&segments)
+    }
+
+    fn unexpected(&self, found: u8, expected: &'static str) -> BencodeError {
+        self.unexpected_at(self.pos, found, expected)
+    }
+
+    fn unexpected_at(&self, index: usize, found: u8, expected: &'static str) -> BencodeError {
+        BencodeError::UnexpectedByte {
+            offset: self.consumed + index,
+            found,
+            expected,
+            path: self.path_string(),
+        }
+    }
+
+    fn invalid_length(&self) -> BencodeError {
+        BencodeError::InvalidLength {
+            offset: self.consumed + self.pos,
+            path: self.path_string(),
+        }
+    }
+
+    fn limit_exceeded(&self, limit: &'static str) -> BencodeError {
+        BencodeError::LimitExceeded {
+            offset: self.consumed + self.pos,
+            limit,
+            path: self.path_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode_decode::decode_bencoded_strict;
+
+    fn limited(max_depth: usize, max_size: usize, max_string_len: usize) -> StreamDecoder {
+        StreamDecoder::new(DecodeLimits {
+            max_depth,
+            max_size,
+            max_string_len,
+        })
+    }
+
+    #[test]
+    fn values_split_at_every_byte_decode_the_same() {
+        let value = b"d3:bari-12e3:fool1:a2:bcd1:xi0eee4:zeroi0ee";
+        let expected = decode_bencoded_strict(value).unwrap();
+        let input = [&value[..], b"rest"].concat();
+
+        for split in 0..=input.len() {
+            let mut decoder = StreamDecoder::new(DecodeLimits::default());
+            decoder.push(&input[..split]);
+
+            let mut pushed = split;
+            let decoded = match decoder.next_value().unwrap() {
+                Decoded::NeedMore => {
+                    assert!(split < value.len(), "split at {}", split);
+                    decoder.push(&input[split..]);
+                    pushed = input.len();
+                    decoder.next_value().unwrap()
+                }
+                decoded => decoded,
+            };
+
+            assert_eq!(
+                decoded,
+                Decoded::Value(expected.clone()),
+                "split at {}",
+                split
+            );
+            assert_eq!(decoder.remaining(), &input[value.len()..pushed]);
+        }
+    }
+
+    #[test]
+    fn remaining_holds_the_bytes_after_a_value() {
+        let mut decoder = StreamDecoder::new(DecodeLimits::default());
+        decoder.push(b"d8:msg_typei1ee\x00\x01\x02");
+
+        assert!(matches!(decoder.next_value(), Ok(Decoded::Value(_))));
+        assert_eq!(decoder.remaining(), b"\x00\x01\x02");
+
+        let mut decoder = StreamDecoder::new(DecodeLimits::default());
+        decoder.push(b"i1e4:spam");
+        assert_eq!(decoder.next_value(), Ok(Decoded::Value(BenValue::Int(1))));
+        assert_eq!(decoder.remaining(), b"4:spam");
+        assert_eq!(
+            decoder.next_value(),
+            Ok(Decoded::Value(BenValue::Bytes(b"spam".to_vec())))
+        );
+        assert!(decoder.remaining().is_empty());
+        assert_eq!(decoder.next_value(), Ok(Decoded::NeedMore));
+    }
+
+    #[test]
+    fn depth_limit_applies_to_the_whole_decode() {
+        let nested = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+
+        let mut decoder = limited(100, 1024, 1024);
+        decoder.push(&nested(100));
+        assert!(matches!(decoder.next_value(), Ok(Decoded::Value(_))));
+
+        let mut decoder = limited(100, 1024, 1024);
+        decoder.push(&nested(101));
+        assert!(matches!(
+            decoder.next_value(),
+            Err(BencodeError::LimitExceeded { limit: "depth", .. })
+        ));
+    }
+
+    #[test]
+    fn size_limit_is_checked_before_the_value_is_complete() {
+        let mut decoder = limited(8, 10, 1024);
+        decoder.push(b"li1ei2ei3e");
+        assert_eq!(decoder.next_value(), Ok(Decoded::NeedMore));
+        decoder.push(b"i4e");
+        assert!(matches!(
+            decoder.next_value(),
+            Err(BencodeError::LimitExceeded { limit: "size", .. })
+        ));
+
+        // a string header already says the value won't fit
+        let mut decoder = limited(8, 10, 1024);
+        decoder.push(b"20:");
+        assert!(matches!(
+            decoder.next_value(),
+            Err(BencodeError::LimitExceeded { limit: "size", .. })
+        ));
+    }
+
+    #[test]
+    fn string_length_limit_is_checked_from_the_header() {
+        let mut decoder = limited(8, 1024, 4);
+        decoder.push(b"d3:key5:");
+        assert!(matches!(
+            decoder.next_value(),
+            Err(BencodeError::LimitExceeded {
+                limit: "string length",
+                ..
+            })
+        ));
+
+        let mut decoder = limited(8, 1024, 4);
+        decoder.push(b"4:abcd");
+        assert_eq!(
+            decoder.next_value(),
+            Ok(Decoded::Value(BenValue::Bytes(b"abcd".to_vec())))
+        );
+    }
+
+    #[test]
+    fn errors_carry_the_path_and_stream_offset() {
+        let mut decoder = StreamDecoder::new(DecodeLimits::default());
+        decoder.push(b"i1ed3:keyx");
+        assert!(matches!(decoder.next_value(), Ok(Decoded::Value(_))));
+
+        let err = decoder.next_value().unwrap_err();
+        assert_eq!(err.offset(), 9);
+        assert_eq!(err.path(), "key");
+    }
+}
@@ -1,3 +1,4 @@
+use bittorrent_starter_rust::models::{engine::DEFAULT_MAX_PEERS, pipeline::DEFAULT_MAX_IN_FLIGHT};
 use clap::{Parser, Subcommand};
 
 #[derive(Parser)]
@@ -10,14 +11,77 @@ pub struct Cli {
 #[clap(rename_all = "snake_case")]
 pub enum Commands {
     Decode {
-        encoded_value: String,
+        /// Bencoded value; read from --file or stdin when omitted
+        encoded_value: Option<String>,
+        /// Read the bencoded input from a file ("-" for stdin)
+        #[arg(
+            short,
+            long,
+            value_name = "FILE-PATH",
+            conflicts_with = "encoded_value"
+        )]
+        file: Option<String>,
+        /// Reject input that is not in canonical bencode form
+        #[arg(long)]
+        strict: bool,
+        /// Indent nested structures and render binary strings as hex
+        #[arg(short, long)]
+        pretty: bool,
+        /// Only print the value at this path, e.g. `info.files[0].length`
+        #[arg(short, long, value_name = "PATH")]
+        select: Option<String>,
+    },
+    Encode {
+        /// JSON value; read from --file or stdin when omitted. Byte strings
+        /// can be written as "hex:..." or "base64:..."
+        json: Option<String>,
+        /// Read the JSON input from a file ("-" for stdin)
+        #[arg(short, long, value_name = "FILE-PATH", conflicts_with = "json")]
+        file: Option<String>,
+        /// Write the bencode to a file instead of stdout
+        #[arg(short, long, value_name = "FILE-PATH")]
+        out: Option<String>,
     },
     Info {
+        /// .torrent file or magnet link
         path: String,
     },
     Peers {
         path: String,
     },
+    Create {
+        /// File or directory to share
+        path: String,
+        #[arg(short, long, value_name = "FILE-PATH")]
+        out: String,
+        /// Tracker URL; repeat for more tiers, separate trackers of one tier with commas
+        #[arg(short, long, value_name = "URL")]
+        announce: Vec<String>,
+        #[arg(short, long)]
+        comment: Option<String>,
+        /// Mark the torrent private (no DHT or peer exchange)
+        #[arg(long)]
+        private: bool,
+        /// Source tag, used by private trackers to tell their torrents apart
+        #[arg(short, long)]
+        source: Option<String>,
+        /// Piece length in bytes; picked from the payload size when omitted
+        #[arg(long = "piece-length", value_name = "BYTES")]
+        piece_length: Option<u64>,
+    },
+    MagnetParse {
+        /// magnet:?xt=urn:btih:... link to parse
+        #[arg(required_unless_present = "torrent")]
+        link: Option<String>,
+        /// Print the magnet link of this torrent instead
+        #[arg(long, value_name = "FILE-PATH", conflicts_with = "link")]
+        torrent: Option<String>,
+    },
+    Verify {
+        path: String,
+        /// Downloaded data, i.e. the path given to `download --out`
+        data: String,
+    },
     Handshake {
         path: String,
         peer: String,
@@ -25,12 +89,23 @@ pub enum Commands {
     DownloadPiece {
         #[arg(short, long, value_name = "FILE-PATH")]
         out: String,
+        /// .torrent file or magnet link
         path: String,
         piece_index: u32,
+        /// Most block requests kept outstanding to one peer
+        #[arg(long = "max-in-flight", value_name = "N", default_value_t = DEFAULT_MAX_IN_FLIGHT)]
+        max_in_flight: usize,
     },
     Download {
         #[arg(short, long, value_name = "FILE-PATH")]
         out: String,
+        /// .torrent file or magnet link
         path: String,
+        /// Most block requests kept outstanding to one peer
+        #[arg(long = "max-in-flight", value_name = "N", default_value_t = DEFAULT_MAX_IN_FLIGHT)]
+        max_in_flight: usize,
+        /// Most peers downloaded from at once
+        #[arg(long = "max-peers", value_name = "N", default_value_t = DEFAULT_MAX_PEERS)]
+        max_peers: usize,
     },
 }
@@ -0,0 +1,7 @@
+pub mod bencode_de;
+pub mod bencode_decode;
+pub mod bencode_encode;
+pub mod bencode_ref;
+pub mod bencode_ser;
+pub mod bencode_stream;
+pub mod models;
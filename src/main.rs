@@ -1,30 +1,98 @@
-mod bencode_decode;
 mod cli_cmd;
-mod models;
 
-use std::io::Error;
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::path::Path;
 
-use crate::{bencode_decode::decode_bencoded_values, models::info::MetaInfo};
+use bittorrent_starter_rust::{
+    bencode_decode::{decode_bencoded_strict, decode_bencoded_values, BytesRendering},
+    bencode_encode::{bencode_from_json, encode_bencoded_value},
+    models::{
+        create::{create_meta_info, CreateOptions},
+        download::Downloader,
+        engine::{DownloadProgress, Engine},
+        handshake::{check_piece_index, HandShake},
+        hasher::HashProgress,
+        info::MetaInfo,
+        magnet::MagnetLink,
+        metadata::download_metadata,
+        peers::{Peer, PeerPool, SharedPeerPool},
+        storage::Storage,
+        tracker::TrackerRequest,
+        verify::{verify_storage, PieceState},
+    },
+};
 use clap::Parser;
 use cli_cmd::{Cli, Commands};
-use models::{handshake::HandShake, tracker::TrackerRequest};
 
-fn main() -> Result<(), Error> {
-    let cli = Cli::parse();
+const MIB: f64 = 1024.0 * 1024.0;
 
+fn main() {
+    // `Display` rather than the `Debug` form `main` returning an error prints
+    if let Err(err) = run(Cli::parse()) {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Error> {
     match cli.subcmd {
-        Commands::Decode { encoded_value } => {
-            let decoded_value = decode_bencoded_values(&encoded_value.as_bytes());
+        Commands::Decode {
+            encoded_value,
+            file,
+            strict,
+            pretty,
+            select,
+        } => {
+            let input = match (encoded_value, file.as_deref()) {
+                (Some(encoded_value), _) => encoded_value.into_bytes(),
+                (None, Some(path)) if path != "-" => std::fs::read(path)?,
+                (None, _) => read_stdin()?,
+            };
 
-            for value in decoded_value.as_array().unwrap() {
-                println!("{}", value);
+            let decoded_values = if strict {
+                vec![decode_bencoded_strict(&input)?]
+            } else {
+                decode_bencoded_values(&input)?
+            };
+
+            for value in &decoded_values {
+                let value = match &select {
+                    Some(path) => value.select(path).ok_or_else(|| {
+                        Error::new(ErrorKind::NotFound, format!("No value at {}", path))
+                    })?,
+                    None => value,
+                };
+
+                if pretty {
+                    let json = value.to_json_with(BytesRendering::Hex);
+                    println!("{}", serde_json::to_string_pretty(&json)?);
+                } else {
+                    println!("{}", value.to_json());
+                }
+            }
+        }
+        Commands::Encode { json, file, out } => {
+            let input = match (json, file.as_deref()) {
+                (Some(json), _) => json.into_bytes(),
+                (None, Some(path)) if path != "-" => std::fs::read(path)?,
+                (None, _) => read_stdin()?,
+            };
+
+            let json: serde_json::Value = serde_json::from_slice(&input)?;
+            let value =
+                bencode_from_json(&json).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+            let encoded = encode_bencoded_value(&value);
+
+            match out {
+                Some(out) => std::fs::write(out, encoded)?,
+                None => io::stdout().write_all(&encoded)?,
             }
         }
         Commands::Info { path } => {
-            let meta_info = MetaInfo::from_file(&path);
+            let (meta_info, _) = load_meta_info(&path)?;
 
             println!("Tracker URL: {}", meta_info.announce);
-            println!("Length: {}", meta_info.info.length);
+            println!("Length: {}", meta_info.info.total_length());
             println!("Info Hash: {}", meta_info.info_hash_str());
             println!("Piece Length: {}", meta_info.info.piece_length);
             println!("Piece Hashes: ");
@@ -33,11 +101,20 @@ fn main() -> Result<(), Error> {
                 for x in chunk {
                     print!("{:02x}", x);
                 }
-                println!("");
+                println!();
+            }
+
+            if let Some(files) = &meta_info.info.files {
+                println!("Files:");
+                for file in files {
+                    println!("{} ({} bytes)", file.path.join("/"), file.length);
+                }
             }
+
+            print_optional_fields(&meta_info);
         }
         Commands::Peers { path } => {
-            let meta_info = MetaInfo::from_file(&path);
+            let meta_info = MetaInfo::from_file(&path)?;
 
             let tracker_request = TrackerRequest::new(
                 &meta_info.announce,
@@ -46,19 +123,128 @@ fn main() -> Result<(), Error> {
                 6881,
                 0,
                 0,
-                meta_info.info.length.to_string().as_str(),
+                meta_info.info.total_length().to_string().as_str(),
             );
 
-            let peers = tracker_request.get_peers();
+            let peers = tracker_request.get_peers()?;
 
             for peer in peers {
                 println!("{}:{}", peer.ip, peer.port);
             }
         }
+        Commands::Create {
+            path,
+            out,
+            announce,
+            comment,
+            private,
+            source,
+            piece_length,
+        } => {
+            let options = CreateOptions {
+                trackers: announce
+                    .iter()
+                    .map(|tier| tier.split(',').map(|url| url.trim().to_string()).collect())
+                    .collect(),
+                comment,
+                private,
+                source,
+                piece_length,
+            };
+
+            let meta_info = create_meta_info(Path::new(&path), &options, print_hash_progress)?;
+            eprintln!();
+            std::fs::write(&out, meta_info.to_bytes())?;
+
+            println!("Created {} from {}", out, path);
+            println!("Info Hash: {}", meta_info.info_hash_str());
+        }
+        Commands::MagnetParse { link, torrent } => {
+            if let Some(torrent) = torrent {
+                let meta_info = MetaInfo::from_file(&torrent)?;
+                println!("{}", MagnetLink::from_meta_info(&meta_info));
+                return Ok(());
+            }
+
+            let link = link.unwrap_or_default();
+            let magnet =
+                MagnetLink::parse(&link).map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+
+            if let Some(tracker) = magnet.trackers.first() {
+                println!("Tracker URL: {}", tracker);
+            }
+            println!("Info Hash: {}", magnet.info_hash_str());
+            if let Some(name) = &magnet.display_name {
+                println!("Name: {}", name);
+            }
+            for tracker in magnet.trackers.iter().skip(1) {
+                println!("Tracker URL: {}", tracker);
+            }
+            for web_seed in &magnet.web_seeds {
+                println!("Web Seed: {}", web_seed);
+            }
+            for peer in &magnet.peers {
+                println!("Peer: {}", peer);
+            }
+            if !magnet.select_only.is_empty() {
+                let files: Vec<String> = magnet
+                    .select_only
+                    .iter()
+                    .flat_map(|range| range.clone().map(|index| index.to_string()))
+                    .collect();
+                println!("Selected Files: {}", files.join(", "));
+            }
+        }
+        Commands::Verify { path, data } => {
+            let meta_info = MetaInfo::from_file(&path)?;
+            let storage = Storage::new(&meta_info.info, Path::new(&data))?;
+
+            let report = verify_storage(&meta_info.info, &storage, print_hash_progress)?;
+            eprintln!();
+
+            println!(
+                "Pieces: {} total, {} complete, {} missing, {} corrupt",
+                report.pieces.len(),
+                report.count(PieceState::Complete),
+                report.count(PieceState::Missing),
+                report.count(PieceState::Corrupt)
+            );
+
+            for state in [PieceState::Missing, PieceState::Corrupt] {
+                let indexes: Vec<String> = report
+                    .pieces
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &piece)| piece == state)
+                    .map(|(index, _)| index.to_string())
+                    .collect();
+
+                if !indexes.is_empty() {
+                    println!("{:?} pieces: {}", state, indexes.join(", "));
+                }
+            }
+
+            println!("Files:");
+            for file in &report.files {
+                println!(
+                    "{}: {} complete, {} missing, {} corrupt",
+                    file.path.display(),
+                    file.complete,
+                    file.missing,
+                    file.corrupt
+                );
+            }
+
+            println!("Bitfield: {}", hex::encode(report.bitfield()));
+
+            if !report.is_complete() {
+                std::process::exit(1);
+            }
+        }
         Commands::Handshake { path, peer } => {
-            let meta_info = MetaInfo::from_file(&path);
+            let meta_info = MetaInfo::from_file(&path)?;
 
-            let addr_port = &peer.split(":").collect::<Vec<&str>>();
+            let addr_port = &peer.split(':').collect::<Vec<&str>>();
 
             let mut handshake = HandShake::new(
                 &meta_info.info_hash(),
@@ -75,68 +261,161 @@ fn main() -> Result<(), Error> {
             out,
             path,
             piece_index,
+            max_in_flight,
         } => {
-            let meta_info = MetaInfo::from_file(&path);
+            let (meta_info, pool) = load_meta_info(&path)?;
+            check_piece_index(&meta_info, piece_index as usize)?;
+            let pool = match pool {
+                Some(pool) => pool,
+                None => PeerPool::shared(tracker_peers(&meta_info)?),
+            };
 
-            let tracker_request = TrackerRequest::new(
-                &meta_info.announce,
-                &meta_info.info_hash(),
-                "00112233445566778899".to_string(),
-                6881,
-                0,
-                0,
-                meta_info.info.length.to_string().as_str(),
-            );
+            let mut downloader = Downloader::new(&meta_info, pool, "00112233445566778899");
+            downloader.max_in_flight = max_in_flight;
+            let file_chunks = downloader.download_piece(piece_index as usize)?;
 
-            let peers = tracker_request.get_peers();
+            std::fs::write(&out, file_chunks).expect("Unable to write file");
 
-            let peer = &peers[1];
+            println!("Piece {} downloaded to {}", piece_index, out);
+        }
 
-            let mut handshake = HandShake::new(
-                &meta_info.info_hash(),
-                &peer.ip.as_str(),
-                peer.port,
-                "00112233445566778899",
-            );
+        Commands::Download {
+            out,
+            path,
+            max_in_flight,
+            max_peers,
+        } => {
+            let (meta_info, pool) = load_meta_info(&path)?;
+            let pool = match pool {
+                Some(pool) => pool,
+                None => PeerPool::shared(tracker_peers(&meta_info)?),
+            };
 
-            let file_chunks = handshake.download_piece(piece_index as usize, &meta_info);
+            let storage = Storage::new(&meta_info.info, Path::new(&out))?;
+            storage.allocate()?;
 
-            std::fs::write(&out, file_chunks).expect("Unable to write file");
+            let mut engine = Engine::new(&meta_info, pool, "00112233445566778899");
+            engine.max_in_flight = max_in_flight;
+            engine.max_peers = max_peers;
+            engine.download(&storage, print_download_progress)?;
+            eprintln!();
 
-            println!("Piece {} downloaded to {}", piece_index, out);
+            println!("Downloaded {} to {}", path, out);
         }
+    }
 
-        Commands::Download { out, path } => {
-            let meta_info = MetaInfo::from_file(&path);
+    Ok(())
+}
 
-            let tracker_request = TrackerRequest::new(
-                &meta_info.announce,
-                &meta_info.info_hash(),
-                "00112233445566778899".to_string(),
-                6881,
-                0,
-                0,
-                meta_info.info.length.to_string().as_str(),
-            );
+// a .torrent path, or a magnet link whose metadata is fetched from peers;
+// the latter also returns the peers found while fetching it
+fn load_meta_info(path: &str) -> Result<(MetaInfo, Option<SharedPeerPool>), Error> {
+    if !path.starts_with("magnet:") {
+        return Ok((MetaInfo::from_file(path)?, None));
+    }
 
-            let peers = tracker_request.get_peers();
+    let magnet = MagnetLink::parse(path).map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    let (meta_info, pool) = download_metadata(&magnet, "00112233445566778899")?;
 
-            let peer = &peers[1];
+    Ok((meta_info, Some(pool)))
+}
 
-            let mut handshake = HandShake::new(
-                &meta_info.info_hash(),
-                &peer.ip.as_str(),
-                peer.port,
-                "00112233445566778899",
-            );
+// none without an HTTP tracker, e.g. for a trackerless torrent
+fn tracker_peers(meta_info: &MetaInfo) -> Result<Vec<Peer>, Error> {
+    if !TrackerRequest::is_supported(&meta_info.announce) {
+        return Ok(Vec::new());
+    }
 
-            let file_chunks = handshake.download_all_pieces(meta_info);
+    let tracker_request = TrackerRequest::new(
+        &meta_info.announce,
+        &meta_info.info_hash(),
+        "00112233445566778899".to_string(),
+        6881,
+        0,
+        0,
+        meta_info.info.total_length().to_string().as_str(),
+    );
 
-            std::fs::write(&out, file_chunks).expect("Unable to write file");
+    tracker_request.get_peers()
+}
 
-            println!("Downloaded {} to {}", path, out);
+// reads all of stdin as-is; a trailing newline may be part of a byte string
+fn read_stdin() -> Result<Vec<u8>, Error> {
+    let mut input = Vec::new();
+    io::stdin().read_to_end(&mut input)?;
+
+    Ok(input)
+}
+
+fn print_optional_fields(meta_info: &MetaInfo) {
+    let info = &meta_info.info;
+
+    println!("Private: {}", if info.is_private() { "yes" } else { "no" });
+    if let Some(source) = &info.source {
+        println!("Source: {}", String::from_utf8_lossy(source));
+    }
+    if let Some(md5sum) = &info.md5sum {
+        println!("MD5: {}", md5sum);
+    }
+    if let Some(tiers) = &meta_info.announce_list {
+        println!("Announce List:");
+        for (tier, trackers) in tiers.iter().enumerate() {
+            println!("  tier {}: {}", tier, trackers.join(", "));
         }
     }
+    if let Some(comment) = &meta_info.comment {
+        println!("Comment: {}", String::from_utf8_lossy(comment));
+    }
+    if let Some(created_by) = &meta_info.created_by {
+        println!("Created By: {}", String::from_utf8_lossy(created_by));
+    }
+    if let Some(creation_date) = meta_info.creation_date {
+        println!("Creation Date: {}", creation_date);
+    }
+    if let Some(encoding) = &meta_info.encoding {
+        println!("Encoding: {}", String::from_utf8_lossy(encoding));
+    }
+    if let Some(urls) = &meta_info.url_list {
+        println!("URL List: {}", urls.join(", "));
+    }
+    if let Some(seeds) = &meta_info.httpseeds {
+        println!("HTTP Seeds: {}", seeds.join(", "));
+    }
+    if let Some(nodes) = &meta_info.nodes {
+        let nodes: Vec<String> = nodes
+            .iter()
+            .map(|(host, port)| format!("{}:{}", host, port))
+            .collect();
+        println!("Nodes: {}", nodes.join(", "));
+    }
 
-    Ok(())
+    let unknown: Vec<_> = meta_info
+        .extra
+        .keys()
+        .chain(info.extra.keys())
+        .map(|key| String::from_utf8_lossy(key))
+        .collect();
+    if !unknown.is_empty() {
+        println!("Other Keys: {}", unknown.join(", "));
+    }
+}
+
+fn print_download_progress(progress: &DownloadProgress) {
+    eprint!(
+        "\rDownloaded {}/{} pieces, {:.1} MiB at {:.1} MiB/s from {} peers",
+        progress.pieces_done,
+        progress.piece_count,
+        progress.bytes_done as f64 / MIB,
+        progress.throughput() / MIB,
+        progress.peers
+    );
+}
+
+fn print_hash_progress(progress: &HashProgress) {
+    eprint!(
+        "\rHashed {} pieces, {:.1} MiB at {:.1} MiB/s",
+        progress.pieces_done,
+        progress.bytes_done as f64 / MIB,
+        progress.throughput() / MIB
+    );
 }
@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_bytes::ByteBuf;
+
+use super::hasher::{HashProgress, PieceHasher};
+use super::info::{FileInfo, Info, MetaInfo};
+use super::storage::StorageFile;
+
+pub const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+pub const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+const TARGET_PIECE_COUNT: u64 = 1500;
+
+#[derive(Debug, Default)]
+pub struct CreateOptions {
+    /// Tracker tiers; the first tracker of the first tier becomes `announce`.
+    pub trackers: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub private: bool,
+    pub source: Option<String>,
+    /// Chosen from the payload size when not set. Must be a power of two
+    /// between `MIN_PIECE_LENGTH` and `MAX_PIECE_LENGTH`.
+    pub piece_length: Option<u64>,
+}
+
+/// Hashes a file or directory into a new torrent, reporting hashing
+/// progress as it goes.
+pub fn create_meta_info(
+    path: &Path,
+    options: &CreateOptions,
+    progress: impl FnMut(&HashProgress),
+) -> Result<MetaInfo, Error> {
+    if let Some(length) = options.piece_length {
+        if !length.is_power_of_two() || !(MIN_PIECE_LENGTH..=MAX_PIECE_LENGTH).contains(&length) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Piece length must be a power of two from {} to {}",
+                    MIN_PIECE_LENGTH, MAX_PIECE_LENGTH
+                ),
+            ));
+        }
+    }
+
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Path has no usable file name"))?
+        .to_string();
+
+    let (length, files) = if path.is_dir() {
+        let mut relative_paths = Vec::new();
+        collect_files(path, &mut Vec::new(), &mut relative_paths)?;
+
+        let files = relative_paths
+            .into_iter()
+            .map(|relative| {
+                let length = fs::metadata(path.join(relative.iter().collect::<PathBuf>()))?.len();
+                Ok(FileInfo {
+                    length: length as i64,
+                    path: relative,
+                    md5sum: None,
+                    extra: BTreeMap::new(),
+                })
+            })
+            .collect::<Result<Vec<FileInfo>, Error>>()?;
+
+        (None, Some(files))
+    } else {
+        (Some(fs::metadata(path)?.len() as i64), None)
+    };
+
+    let mut info = Info {
+        length,
+        files,
+        name,
+        piece_length: 0,
+        pieces: ByteBuf::new(),
+        private: options.private.then_some(1),
+        source: options.source.clone().map(ByteBuf::from),
+        md5sum: None,
+        extra: BTreeMap::new(),
+    };
+    info.piece_length = options
+        .piece_length
+        .unwrap_or_else(|| auto_piece_length(info.total_length()));
+
+    let payload: Vec<StorageFile> = match &info.files {
+        Some(files) => {
+            let mut offset = 0;
+            files
+                .iter()
+                .map(|file| {
+                    let storage_file = StorageFile {
+                        path: path.join(file.path.iter().collect::<PathBuf>()),
+                        offset,
+                        length: file.length as u64,
+                    };
+                    offset += file.length as u64;
+                    storage_file
+                })
+                .collect()
+        }
+        None => vec![StorageFile {
+            path: path.to_path_buf(),
+            offset: 0,
+            length: info.total_length(),
+        }],
+    };
+
+    let hashes = PieceHasher::with_available_parallelism().hash_files(
+        &payload,
+        info.piece_length,
+        progress,
+    )?;
+    let mut pieces = Vec::with_capacity(hashes.len() * 20);
+    for hash in hashes {
+        let hash = hash.ok_or_else(|| {
+            Error::new(ErrorKind::UnexpectedEof, "File changed while it was hashed")
+        })?;
+        pieces.extend(hash);
+    }
+    info.pieces = ByteBuf::from(pieces);
+
+    // left empty without trackers, so the key isn't written
+    let announce = options
+        .trackers
+        .first()
+        .and_then(|tier| tier.first())
+        .cloned()
+        .unwrap_or_default();
+    // `announce` alone can only hold one tracker
+    let tracker_count: usize = options.trackers.iter().map(Vec::len).sum();
+    let creation_date = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs() as i64)
+        .ok();
+
+    Ok(MetaInfo {
+        announce,
+        announce_list: (tracker_count > 1).then(|| options.trackers.clone()),
+        comment: options.comment.clone().map(ByteBuf::from),
+        created_by: Some(ByteBuf::from(format!(
+            "{} {}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        ))),
+        creation_date,
+        encoding: None,
+        url_list: None,
+        httpseeds: None,
+        nodes: None,
+        info,
+        extra: BTreeMap::new(),
+        raw_info_hash: None,
+    })
+}
+
+// smallest power of two that keeps the piece count near the target
+fn auto_piece_length(total_length: u64) -> u64 {
+    let mut piece_length = MIN_PIECE_LENGTH;
+
+    while piece_length < MAX_PIECE_LENGTH && total_length / piece_length > TARGET_PIECE_COUNT {
+        piece_length *= 2;
+    }
+
+    piece_length
+}
+
+// files below `dir` in a stable (sorted) order, as path components
+fn collect_files(
+    dir: &Path,
+    prefix: &mut Vec<String>,
+    out: &mut Vec<Vec<String>>,
+) -> Result<(), Error> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, Error>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = entry.file_name().into_string().map_err(|name| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("File name is not UTF-8: {:?}", name),
+            )
+        })?;
+
+        prefix.push(name);
+        if entry.file_type()?.is_dir() {
+            collect_files(&entry.path(), prefix, out)?;
+        } else {
+            out.push(prefix.clone());
+        }
+        prefix.pop();
+    }
+
+    Ok(())
+}
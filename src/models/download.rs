@@ -0,0 +1,134 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+
+use super::handshake::{check_piece_index, HandShake, HashMismatch};
+use super::info::MetaInfo;
+use super::peers::{Peer, SharedPeerPool};
+use super::pex::PexHandler;
+use super::pipeline::DEFAULT_MAX_IN_FLIGHT;
+
+/// Downloads pieces from the peers in a pool, moving a piece to another
+/// peer when one fails it.
+pub struct Downloader<'a> {
+    meta_info: &'a MetaInfo,
+    pool: SharedPeerPool,
+    peer_id: String,
+    /// Cap on outstanding block requests per peer.
+    pub max_in_flight: usize,
+    // peers not to ask for a piece again: they sent bad data for it or
+    // don't have it
+    skip: HashMap<usize, HashSet<Peer>>,
+}
+
+impl<'a> Downloader<'a> {
+    pub fn new(meta_info: &'a MetaInfo, pool: SharedPeerPool, peer_id: &str) -> Downloader<'a> {
+        Downloader {
+            meta_info,
+            pool,
+            peer_id: peer_id.to_string(),
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            skip: HashMap::new(),
+        }
+    }
+
+    /// Downloads and verifies one piece, trying peers until one delivers it.
+    pub fn download_piece(&mut self, piece_index: usize) -> Result<Vec<u8>, Error> {
+        check_piece_index(self.meta_info, piece_index)?;
+        let mut last_error = None;
+
+        while let Some(peer) = self.next_peer(piece_index) {
+            let mut handshake = self.connect(&peer);
+            self.pool
+                .lock()
+                .expect("Peer pool poisoned")
+                .mark_connected(&peer);
+
+            let result = handshake.download_piece(piece_index, self.meta_info);
+
+            let mut pool = self.pool.lock().expect("Peer pool poisoned");
+            pool.mark_disconnected(&peer);
+
+            match result {
+                Ok(piece) => {
+                    pool.requeue(peer);
+                    return Ok(piece);
+                }
+                Err(err) if HashMismatch::is(&err) => {
+                    self.skip
+                        .entry(piece_index)
+                        .or_default()
+                        .insert(peer.clone());
+                    if !pool.record_hash_failure(&peer) {
+                        pool.requeue(peer.clone());
+                    }
+                    last_error = Some(format!("{}: {}", peer, err));
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    self.skip
+                        .entry(piece_index)
+                        .or_default()
+                        .insert(peer.clone());
+                    pool.requeue(peer.clone());
+                    last_error = Some(format!("{}: {}", peer, err));
+                }
+                // the connection failed, so the peer isn't tried again
+                Err(err) => last_error = Some(format!("{}: {}", peer, err)),
+            }
+        }
+
+        let message = match last_error {
+            Some(err) => format!("No peer left for piece {} ({})", piece_index, err),
+            None => format!("No peer left for piece {}", piece_index),
+        };
+        Err(Error::new(ErrorKind::NotFound, message))
+    }
+
+    // next queued peer that may still be asked for this piece
+    fn next_peer(&self, piece_index: usize) -> Option<Peer> {
+        let skip = self.skip.get(&piece_index);
+        let mut pool = self.pool.lock().expect("Peer pool poisoned");
+
+        let mut skipped = Vec::new();
+        let peer = loop {
+            match pool.next_candidate() {
+                Some(peer) if skip.is_some_and(|skip| skip.contains(&peer)) => skipped.push(peer),
+                peer => break peer,
+            }
+        };
+
+        for peer in skipped {
+            pool.requeue(peer);
+        }
+
+        peer
+    }
+
+    fn connect(&self, peer: &Peer) -> HandShake {
+        let mut handshake = peer_connection(self.meta_info, &self.pool, peer, &self.peer_id);
+        handshake.max_in_flight = self.max_in_flight;
+
+        handshake
+    }
+}
+
+// a connection to `peer` offering the extensions the torrent allows
+pub(crate) fn peer_connection(
+    meta_info: &MetaInfo,
+    pool: &SharedPeerPool,
+    peer: &Peer,
+    peer_id: &str,
+) -> HandShake {
+    let mut handshake = HandShake::new(&meta_info.info_hash(), &peer.ip, peer.port, peer_id);
+    handshake.piece_count = Some(meta_info.info.piece_count());
+    handshake.enable_fast();
+
+    // BEP 27: private torrents only get peers from their tracker
+    if !meta_info.info.is_private() {
+        handshake.enable_extensions();
+        handshake
+            .extensions
+            .register(Box::new(PexHandler::new(pool.clone(), peer.clone())));
+    }
+
+    handshake
+}
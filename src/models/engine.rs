@@ -0,0 +1,961 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use sha1::{Digest, Sha1};
+
+use super::download::peer_connection;
+use super::handshake::{HandShake, PeerWriter};
+use super::info::{Info, MetaInfo};
+use super::message::Message;
+use super::peers::{Peer, SharedPeerPool};
+use super::picker::PiecePicker;
+use super::pipeline::{PeerRequests, RequestUpdate, BLOCK_SIZE, DEFAULT_MAX_IN_FLIGHT};
+use super::storage::Storage;
+
+/// Default number of peers downloaded from at once.
+pub const DEFAULT_MAX_PEERS: usize = 30;
+
+// peers send a keep-alive at least every two minutes, so a connection
+// silent for longer than that is dead
+const PEER_TIMEOUT: Duration = Duration::from_secs(150);
+// how often a slot without work looks for more: peers learned through
+// PEX, or pieces to download again after a failed hash check
+const IDLE_POLL: Duration = Duration::from_millis(500);
+
+// a piece with all its blocks in, and the peer each block came from
+type CompletePiece = (Vec<u8>, Vec<Peer>);
+
+pub struct DownloadProgress {
+    pub pieces_done: usize,
+    pub piece_count: usize,
+    pub bytes_done: u64,
+    /// Peers connected when the last piece was written.
+    pub peers: usize,
+    pub elapsed: Duration,
+}
+
+impl DownloadProgress {
+    /// Bytes written per second so far.
+    pub fn throughput(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds == 0.0 {
+            return 0.0;
+        }
+
+        self.bytes_done as f64 / seconds
+    }
+}
+
+/// Downloads a torrent from many peers at once.
+///
+/// Each connection runs on its own thread and requests blocks of whichever
+/// missing pieces its peer has, so a piece left half done by a peer that
+/// disconnects is finished by the others. Once every block left has been
+/// requested, connections with room to spare request the same blocks too,
+/// and the other requests for a block are cancelled when it arrives.
+/// Verified pieces are written to storage on the calling thread, which
+/// also reports progress.
+pub struct Engine<'a> {
+    meta_info: &'a MetaInfo,
+    pool: SharedPeerPool,
+    peer_id: String,
+    /// Most peers connected at once.
+    pub max_peers: usize,
+    /// Cap on outstanding block requests per peer.
+    pub max_in_flight: usize,
+}
+
+impl<'a> Engine<'a> {
+    pub fn new(meta_info: &'a MetaInfo, pool: SharedPeerPool, peer_id: &str) -> Engine<'a> {
+        Engine {
+            meta_info,
+            pool,
+            peer_id: peer_id.to_string(),
+            max_peers: DEFAULT_MAX_PEERS,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+        }
+    }
+
+    /// Downloads every piece into `storage`, returning once all of them
+    /// are written or no peer is left to get the rest from.
+    pub fn download(
+        &self,
+        storage: &Storage,
+        mut progress: impl FnMut(&DownloadProgress),
+    ) -> Result<(), Error> {
+        let started = Instant::now();
+        let info = &self.meta_info.info;
+        let swarm = Mutex::new(Swarm::new(info));
+
+        let mut report = DownloadProgress {
+            pieces_done: 0,
+            piece_count: info.piece_count(),
+            bytes_done: 0,
+            peers: 0,
+            elapsed: Duration::ZERO,
+        };
+
+        thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel();
+            for slot in 0..self.max_peers.max(1) {
+                let sender = sender.clone();
+                let swarm = &swarm;
+                scope.spawn(move || self.run_slot(slot, swarm, sender));
+            }
+            drop(sender);
+
+            for (index, piece) in receiver {
+                if let Err(err) = storage.write_piece(index, &piece) {
+                    swarm.lock().expect("Swarm state poisoned").finish();
+                    return Err(err);
+                }
+
+                report.pieces_done += 1;
+                report.bytes_done += piece.len() as u64;
+                report.peers = swarm.lock().expect("Swarm state poisoned").connections;
+                report.elapsed = started.elapsed();
+                progress(&report);
+            }
+
+            Ok(())
+        })?;
+
+        if report.pieces_done < report.piece_count {
+            let swarm = swarm.into_inner().expect("Swarm state poisoned");
+            let mut message = format!(
+                "No peer left for the {} missing pieces",
+                report.piece_count - report.pieces_done
+            );
+            if let Some(err) = swarm.last_error {
+                message.push_str(&format!(" ({})", err));
+            }
+            return Err(Error::new(ErrorKind::NotFound, message));
+        }
+
+        Ok(())
+    }
+
+    // one connection slot: serves peers from the pool one after another
+    // until the download is done or no peer is left
+    fn run_slot(&self, slot: usize, swarm: &Mutex<Swarm>, results: Sender<(usize, Vec<u8>)>) {
+        loop {
+            let peer = {
+                let mut swarm = swarm.lock().expect("Swarm state poisoned");
+                if swarm.finished {
+                    return;
+                }
+
+                let candidate = self
+                    .pool
+                    .lock()
+                    .expect("Peer pool poisoned")
+                    .next_candidate();
+                match candidate {
+                    Some(peer) => {
+                        swarm.connections += 1;
+                        peer
+                    }
+                    // connected peers may still tell us about others
+                    None if swarm.connections > 0 => {
+                        swarm.check_stalled(&self.pool);
+                        drop(swarm);
+                        thread::sleep(IDLE_POLL);
+                        continue;
+                    }
+                    None => return,
+                }
+            };
+
+            self.pool
+                .lock()
+                .expect("Peer pool poisoned")
+                .mark_connected(&peer);
+
+            let mut requests = PeerRequests::new(self.max_in_flight);
+            let result = self.serve_peer(slot, &peer, swarm, &mut requests, &results);
+
+            let mut swarm = swarm.lock().expect("Swarm state poisoned");
+            // blocks the peer still owed us go to the other peers
+            for (index, begin) in requests.outstanding() {
+                swarm.release(slot, index as usize, begin);
+            }
+            swarm.abandon(slot);
+            swarm.cancels.remove(&slot);
+            swarm.streams.remove(&slot);
+            swarm.writers.remove(&slot);
+            swarm.idle.remove(&slot);
+            swarm.picker.remove_peer(slot);
+            swarm.connections -= 1;
+            // errors after we finish come from closing the connection
+            if let (Err(err), false) = (result, swarm.finished) {
+                swarm.last_error = Some(format!("{}: {}", peer, err));
+            }
+            drop(swarm);
+
+            self.pool
+                .lock()
+                .expect("Peer pool poisoned")
+                .mark_disconnected(&peer);
+        }
+    }
+
+    fn serve_peer(
+        &self,
+        slot: usize,
+        peer: &Peer,
+        swarm: &Mutex<Swarm>,
+        requests: &mut PeerRequests,
+        results: &Sender<(usize, Vec<u8>)>,
+    ) -> Result<(), Error> {
+        let mut handshake = peer_connection(self.meta_info, &self.pool, peer, &self.peer_id);
+        handshake.try_handshake()?;
+
+        if let Some(socket) = &handshake.socket {
+            socket.set_read_timeout(Some(PEER_TIMEOUT))?;
+
+            // registered so the connection can be closed once we're done
+            let mut swarm = swarm.lock().expect("Swarm state poisoned");
+            if swarm.finished {
+                return Ok(());
+            }
+            swarm.streams.insert(slot, socket.try_clone()?);
+            if let Some(writer) = handshake.writer() {
+                swarm.writers.insert(slot, writer);
+            }
+        }
+
+        handshake.send_message(&Message::Interested)?;
+        let mut interested = true;
+        // until the peer says which pieces it has, it can't be judged useless
+        let mut knows_pieces = false;
+        let mut last_heard = Instant::now();
+
+        loop {
+            if self
+                .pool
+                .lock()
+                .expect("Peer pool poisoned")
+                .is_banned(peer)
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Peer sent too many pieces that failed their hash check",
+                ));
+            }
+            self.drop_cancelled(slot, swarm, requests);
+            self.request_blocks(slot, &mut handshake, swarm, requests)?;
+
+            if requests.is_empty() && knows_pieces {
+                let wanted = {
+                    let mut swarm = swarm.lock().expect("Swarm state poisoned");
+                    let wanted = swarm.wants_any(|index| {
+                        handshake.peer_has(index) && !requests.rejected_piece(index as u32)
+                    });
+                    swarm.set_idle(slot, !wanted, &self.pool);
+                    wanted
+                };
+
+                if wanted != interested {
+                    interested = wanted;
+                    handshake.send_message(if interested {
+                        &Message::Interested
+                    } else {
+                        &Message::NotInterested
+                    })?;
+                }
+            }
+
+            // with nothing requested the peer may stay silent for long, while
+            // pieces may become available to request again
+            if requests.is_empty() && !handshake.wait_readable(IDLE_POLL)? {
+                if last_heard.elapsed() > PEER_TIMEOUT {
+                    return Err(Error::new(ErrorKind::TimedOut, "Peer went silent"));
+                }
+                continue;
+            }
+
+            let message = handshake.read_message()?;
+            last_heard = Instant::now();
+            match message {
+                Message::BitField(_) | Message::HaveAll | Message::HaveNone => {
+                    knows_pieces = true;
+                    swarm
+                        .lock()
+                        .expect("Swarm state poisoned")
+                        .picker
+                        .set_peer(slot, |index| handshake.peer_has(index));
+                }
+                Message::Have(index) => {
+                    knows_pieces = true;
+                    swarm
+                        .lock()
+                        .expect("Swarm state poisoned")
+                        .picker
+                        .add_have(slot, index as usize);
+                }
+                _ => {}
+            }
+
+            match requests.on_message(&message, &handshake)? {
+                RequestUpdate::Block { index, begin, data } => {
+                    let (cancel_on, complete) = swarm
+                        .lock()
+                        .expect("Swarm state poisoned")
+                        .add_block(slot, index as usize, begin, data, peer);
+
+                    // a connection that fails here is dropped by its own thread
+                    let cancel = Message::Cancel {
+                        index,
+                        begin,
+                        length: data.len() as u32,
+                    };
+                    for writer in cancel_on {
+                        _ = writer.send(&cancel);
+                    }
+
+                    if let Some((data, senders)) = complete {
+                        self.verify_piece(index as usize, data, senders, swarm, results);
+                    }
+                }
+                RequestUpdate::Dropped(blocks) => {
+                    let mut swarm = swarm.lock().expect("Swarm state poisoned");
+                    for (index, begin, _) in blocks {
+                        swarm.release(slot, index as usize, begin);
+                    }
+                    if matches!(message, Message::Choke) {
+                        swarm.abandon(slot);
+                    }
+                }
+                RequestUpdate::None => {}
+            }
+        }
+    }
+
+    // forgets requests for blocks another peer delivered first; that
+    // peer's connection already sent the cancels
+    fn drop_cancelled(&self, slot: usize, swarm: &Mutex<Swarm>, requests: &mut PeerRequests) {
+        let cancels = swarm
+            .lock()
+            .expect("Swarm state poisoned")
+            .cancels
+            .remove(&slot)
+            .unwrap_or_default();
+
+        for (index, begin) in cancels {
+            requests.forget(index, begin);
+        }
+    }
+
+    // fills the request window with blocks the peer can serve right now
+    fn request_blocks(
+        &self,
+        slot: usize,
+        handshake: &mut HandShake,
+        swarm: &Mutex<Swarm>,
+        requests: &mut PeerRequests,
+    ) -> Result<(), Error> {
+        while requests.has_room() {
+            let block = {
+                let handshake = &*handshake;
+                let requests = &*requests;
+                // allowed-fast pieces can be requested while choked; the
+                // swarm hands out blocks by piece, so a rejected block rules
+                // out the rest of its piece too
+                let can_request = |index: usize| {
+                    handshake.peer_has(index)
+                        && (!handshake.choked || handshake.allowed_fast.contains(&(index as u32)))
+                        && !requests.rejected_piece(index as u32)
+                };
+                swarm.lock().expect("Swarm state poisoned").next_block(
+                    slot,
+                    &self.meta_info.info,
+                    can_request,
+                )
+            };
+            let Some((index, begin, length)) = block else {
+                break;
+            };
+
+            requests.send(handshake, index, begin, length)?;
+        }
+
+        Ok(())
+    }
+
+    // checks a piece whose blocks have all arrived and hands it to the
+    // writer; a bad piece counts against the peers proven to have sent
+    // bad data for it
+    fn verify_piece(
+        &self,
+        index: usize,
+        data: Vec<u8>,
+        senders: Vec<Peer>,
+        swarm: &Mutex<Swarm>,
+        results: &Sender<(usize, Vec<u8>)>,
+    ) {
+        let valid = Sha1::digest(&data)[..] == *self.meta_info.info.piece_hash(index);
+        let culprits = swarm
+            .lock()
+            .expect("Swarm state poisoned")
+            .finish_piece(index, &data, senders, valid);
+
+        if !culprits.is_empty() {
+            let mut pool = self.pool.lock().expect("Peer pool poisoned");
+            for peer in &culprits {
+                pool.record_hash_failure(peer);
+            }
+        }
+
+        if valid {
+            // the writer only hangs up after a failed write, which ends
+            // the download anyway
+            let _ = results.send((index, data));
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+enum BlockState {
+    Missing,
+    // by the slots listed; more than one in endgame
+    Requested(Vec<usize>),
+    Received,
+}
+
+struct PartialPiece {
+    data: Vec<u8>,
+    blocks: Vec<BlockState>,
+    // the peer each received block came from
+    senders: Vec<Option<Peer>>,
+    // the only slot allowed to download it, when retrying a piece that
+    // failed with blocks from several peers
+    owner: Option<usize>,
+}
+
+impl PartialPiece {
+    fn next_missing(&self) -> Option<usize> {
+        self.blocks
+            .iter()
+            .position(|block| *block == BlockState::Missing)
+    }
+}
+
+enum PieceState {
+    Missing,
+    Partial(PartialPiece),
+    Verifying,
+    Done,
+}
+
+impl PieceState {
+    // started, with blocks nobody has been asked for yet
+    fn has_unrequested(&self) -> bool {
+        matches!(self, PieceState::Partial(piece) if piece.next_missing().is_some())
+    }
+
+    // started, and open to requests from `slot`
+    fn open_to(&self, slot: usize) -> bool {
+        match self {
+            PieceState::Partial(piece) => piece.owner.unwrap_or(slot) == slot,
+            _ => false,
+        }
+    }
+}
+
+// a piece that failed its hash check with blocks from several peers,
+// kept until a good copy shows which of its blocks were bad
+struct FailedPiece {
+    data: Vec<u8>,
+    senders: Vec<Peer>,
+}
+
+// download state shared by the connections
+struct Swarm {
+    pieces: Vec<PieceState>,
+    // pieces in the `Partial` state
+    partial: BTreeSet<usize>,
+    picker: PiecePicker,
+    // pieces in the `Missing` state
+    unstarted: usize,
+    remaining: usize,
+    connections: usize,
+    // clones of the connected sockets by slot, shut down when we finish
+    streams: HashMap<usize, TcpStream>,
+    // slots whose peer has none of the pieces we still need
+    idle: HashSet<usize>,
+    // write halves of the connected sockets by slot, for sending cancels
+    // from the connection that got the block
+    writers: HashMap<usize, PeerWriter>,
+    // requests each slot had cancelled, as (piece, offset)
+    cancels: HashMap<usize, Vec<(u32, u32)>>,
+    failed: HashMap<usize, FailedPiece>,
+    finished: bool,
+    last_error: Option<String>,
+}
+
+impl Swarm {
+    fn new(info: &Info) -> Swarm {
+        let piece_count = info.piece_count();
+        let mut pieces = Vec::with_capacity(piece_count);
+        pieces.resize_with(piece_count, || PieceState::Missing);
+
+        Swarm {
+            pieces,
+            partial: BTreeSet::new(),
+            picker: PiecePicker::new(piece_count),
+            unstarted: piece_count,
+            remaining: piece_count,
+            connections: 0,
+            streams: HashMap::new(),
+            writers: HashMap::new(),
+            idle: HashSet::new(),
+            cancels: HashMap::new(),
+            failed: HashMap::new(),
+            finished: piece_count == 0,
+            last_error: None,
+        }
+    }
+
+    // a block nobody has requested yet of a piece `can_request` accepts,
+    // as (piece, offset, length); started pieces come first, so they are
+    // verified and written sooner
+    fn next_block(
+        &mut self,
+        slot: usize,
+        info: &Info,
+        can_request: impl Fn(usize) -> bool,
+    ) -> Option<(u32, u32, u32)> {
+        let pieces = &self.pieces;
+        let partial = self.partial.iter().copied().filter(|&index| {
+            can_request(index) && pieces[index].open_to(slot) && pieces[index].has_unrequested()
+        });
+
+        let index = match self.picker.rarest(partial) {
+            Some(index) => index,
+            None if self.in_endgame() => return self.endgame_block(slot, can_request),
+            None => {
+                let completed = pieces.len() - self.remaining;
+                let index = self.picker.pick(completed, |index| {
+                    matches!(pieces[index], PieceState::Missing) && can_request(index)
+                })?;
+
+                let length = info.piece_size(index);
+                let block_count = (length - 1) / BLOCK_SIZE + 1;
+                self.pieces[index] = PieceState::Partial(PartialPiece {
+                    data: vec![0; length],
+                    blocks: vec![BlockState::Missing; block_count],
+                    senders: vec![None; block_count],
+                    // a single peer's copy either passes or proves it bad
+                    owner: self.failed.contains_key(&index).then_some(slot),
+                });
+                self.partial.insert(index);
+                self.unstarted -= 1;
+                index
+            }
+        };
+
+        let PieceState::Partial(piece) = &mut self.pieces[index] else {
+            return None;
+        };
+        let block = piece.next_missing()?;
+        piece.blocks[block] = BlockState::Requested(vec![slot]);
+
+        Some(block_request(index, block, piece.data.len()))
+    }
+
+    // endgame starts once every block left has been requested, so that
+    // the last pieces don't wait on whichever peers are slowest
+    fn in_endgame(&self) -> bool {
+        self.unstarted == 0
+            && self
+                .partial
+                .iter()
+                .all(|&index| !self.pieces[index].has_unrequested())
+    }
+
+    // asks for a block again that other peers were already asked for,
+    // the one with the fewest requests first
+    fn endgame_block(
+        &mut self,
+        slot: usize,
+        can_request: impl Fn(usize) -> bool,
+    ) -> Option<(u32, u32, u32)> {
+        let mut best = None;
+        let mut fewest = usize::MAX;
+        let pieces = &self.pieces;
+        for &index in self
+            .partial
+            .iter()
+            .filter(|&&index| can_request(index) && pieces[index].open_to(slot))
+        {
+            let PieceState::Partial(piece) = &self.pieces[index] else {
+                continue;
+            };
+
+            for (block, state) in piece.blocks.iter().enumerate() {
+                let BlockState::Requested(slots) = state else {
+                    continue;
+                };
+                if slots.len() < fewest && !slots.contains(&slot) {
+                    best = Some((index, block));
+                    fewest = slots.len();
+                }
+            }
+        }
+
+        let (index, block) = best?;
+        let PieceState::Partial(piece) = &mut self.pieces[index] else {
+            return None;
+        };
+        if let BlockState::Requested(slots) = &mut piece.blocks[block] {
+            slots.push(slot);
+        }
+
+        Some(block_request(index, block, piece.data.len()))
+    }
+
+    // whether a peer having the pieces `peer_has` accepts can still help
+    fn wants_any(&self, peer_has: impl Fn(usize) -> bool) -> bool {
+        self.pieces
+            .iter()
+            .enumerate()
+            .any(|(index, state)| !matches!(state, PieceState::Done) && peer_has(index))
+    }
+
+    fn set_idle(&mut self, slot: usize, idle: bool, pool: &SharedPeerPool) {
+        if !idle {
+            self.idle.remove(&slot);
+            return;
+        }
+
+        self.idle.insert(slot);
+        self.check_stalled(pool);
+    }
+
+    // gives up once every connected peer is idle and no other peer is
+    // left to try, since nothing would ever arrive
+    fn check_stalled(&mut self, pool: &SharedPeerPool) {
+        if self.idle.len() < self.connections {
+            return;
+        }
+
+        if !pool.lock().expect("Peer pool poisoned").has_candidates() {
+            self.finish();
+        }
+    }
+
+    // drops a request the slot's peer won't answer, making the block
+    // available again unless another peer was asked for it too
+    fn release(&mut self, slot: usize, index: usize, begin: u32) {
+        let Some(PieceState::Partial(piece)) = self.pieces.get_mut(index) else {
+            return;
+        };
+        let Some(block) = piece.blocks.get_mut(begin as usize / BLOCK_SIZE) else {
+            return;
+        };
+
+        if let BlockState::Requested(slots) = block {
+            slots.retain(|&requester| requester != slot);
+            if slots.is_empty() {
+                *block = BlockState::Missing;
+            }
+        }
+    }
+
+    // stores a block and returns the connections of the other slots that
+    // asked for it, to cancel their requests on, along with the piece and
+    // the peer each block came from once its last block is in
+    fn add_block(
+        &mut self,
+        slot: usize,
+        index: usize,
+        begin: u32,
+        data: &[u8],
+        peer: &Peer,
+    ) -> (Vec<PeerWriter>, Option<CompletePiece>) {
+        let Some(PieceState::Partial(piece)) = self.pieces.get_mut(index) else {
+            return (Vec::new(), None);
+        };
+
+        // a late block from before the piece was restricted to its owner
+        if piece.owner.is_some_and(|owner| owner != slot) {
+            return (Vec::new(), None);
+        }
+
+        let offset = begin as usize;
+        let Some(block) = piece.blocks.get_mut(offset / BLOCK_SIZE) else {
+            return (Vec::new(), None);
+        };
+        if *block == BlockState::Received || offset + data.len() > piece.data.len() {
+            return (Vec::new(), None);
+        }
+
+        let mut cancel_on = Vec::new();
+        if let BlockState::Requested(slots) = std::mem::replace(block, BlockState::Received) {
+            for other in slots.into_iter().filter(|&other| other != slot) {
+                self.cancels
+                    .entry(other)
+                    .or_default()
+                    .push((index as u32, begin));
+                cancel_on.extend(self.writers.get(&other).cloned());
+            }
+        }
+        piece.data[offset..offset + data.len()].copy_from_slice(data);
+        piece.senders[offset / BLOCK_SIZE] = Some(peer.clone());
+
+        if piece
+            .blocks
+            .iter()
+            .any(|block| *block != BlockState::Received)
+        {
+            return (cancel_on, None);
+        }
+        self.partial.remove(&index);
+        match std::mem::replace(&mut self.pieces[index], PieceState::Verifying) {
+            PieceState::Partial(piece) => (
+                cancel_on,
+                Some((piece.data, piece.senders.into_iter().flatten().collect())),
+            ),
+            _ => (cancel_on, None),
+        }
+    }
+
+    // a piece that failed its hash check is downloaded again from scratch;
+    // returns the peers proven to have sent bad data for it: the only
+    // sender of a bad copy, or the senders of blocks that differ from the
+    // good copy once there is one
+    fn finish_piece(
+        &mut self,
+        index: usize,
+        data: &[u8],
+        senders: Vec<Peer>,
+        valid: bool,
+    ) -> Vec<Peer> {
+        if !valid {
+            self.pieces[index] = PieceState::Missing;
+            self.unstarted += 1;
+
+            let mut culprits = senders.clone();
+            culprits.dedup();
+            if culprits.len() == 1 {
+                return culprits;
+            }
+            self.failed.insert(
+                index,
+                FailedPiece {
+                    data: data.to_vec(),
+                    senders,
+                },
+            );
+            return Vec::new();
+        }
+
+        self.pieces[index] = PieceState::Done;
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            self.finish();
+        }
+
+        let Some(failed) = self.failed.remove(&index) else {
+            return Vec::new();
+        };
+        let mut culprits = Vec::new();
+        let blocks = failed.data.chunks(BLOCK_SIZE).zip(data.chunks(BLOCK_SIZE));
+        for ((bad, good), sender) in blocks.zip(failed.senders) {
+            if bad != good && !culprits.contains(&sender) {
+                culprits.push(sender);
+            }
+        }
+        culprits
+    }
+
+    // pieces only the slot may download start over once its peer is gone
+    fn abandon(&mut self, slot: usize) {
+        let owned: Vec<usize> = self
+            .partial
+            .iter()
+            .copied()
+            .filter(|&index| {
+                matches!(&self.pieces[index], PieceState::Partial(piece) if piece.owner == Some(slot))
+            })
+            .collect();
+
+        for index in owned {
+            self.partial.remove(&index);
+            self.pieces[index] = PieceState::Missing;
+            self.unstarted += 1;
+        }
+    }
+
+    // ends the download, waking connections blocked on a read
+    fn finish(&mut self) {
+        self.finished = true;
+        for (_, stream) in self.streams.drain() {
+            _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+fn block_request(index: usize, block: usize, piece_length: usize) -> (u32, u32, u32) {
+    let begin = block * BLOCK_SIZE;
+    (
+        index as u32,
+        begin as u32,
+        BLOCK_SIZE.min(piece_length - begin) as u32,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+
+    const BLOCK: u32 = BLOCK_SIZE as u32;
+
+    // pieces of two full blocks each
+    fn info(piece_count: usize) -> Info {
+        Info {
+            length: Some((piece_count * 2 * BLOCK_SIZE) as i64),
+            files: None,
+            name: "f".to_string(),
+            piece_length: 2 * BLOCK_SIZE as u64,
+            pieces: ByteBuf::from(vec![0; piece_count * 20]),
+            private: None,
+            source: None,
+            md5sum: None,
+            extra: BTreeMap::new(),
+        }
+    }
+
+    fn peer(port: u16) -> Peer {
+        Peer {
+            ip: "10.0.0.1".to_string(),
+            port,
+        }
+    }
+
+    fn next(swarm: &mut Swarm, info: &Info, slot: usize) -> Option<(u32, u32, u32)> {
+        swarm.next_block(slot, info, |_| true)
+    }
+
+    // delivers both blocks of a piece from one slot, returning the piece
+    // once complete
+    fn deliver(
+        swarm: &mut Swarm,
+        slot: usize,
+        index: usize,
+        blocks: [u8; 2],
+        from: &Peer,
+    ) -> Option<CompletePiece> {
+        let (_, first) = swarm.add_block(slot, index, 0, &[blocks[0]; BLOCK_SIZE], from);
+        assert!(first.is_none());
+        swarm
+            .add_block(slot, index, BLOCK, &[blocks[1]; BLOCK_SIZE], from)
+            .1
+    }
+
+    #[test]
+    fn released_blocks_go_to_the_next_request() {
+        let info = info(1);
+        let mut swarm = Swarm::new(&info);
+
+        assert_eq!(next(&mut swarm, &info, 0), Some((0, 0, BLOCK)));
+        // only the slot that asked can release it
+        swarm.release(1, 0, 0);
+        assert_eq!(next(&mut swarm, &info, 1), Some((0, BLOCK, BLOCK)));
+
+        swarm.release(0, 0, 0);
+        assert_eq!(next(&mut swarm, &info, 2), Some((0, 0, BLOCK)));
+    }
+
+    #[test]
+    fn endgame_asks_again_for_the_least_requested_block() {
+        let info = info(1);
+        let mut swarm = Swarm::new(&info);
+
+        next(&mut swarm, &info, 0);
+        next(&mut swarm, &info, 1);
+        assert!(swarm.in_endgame());
+
+        // never the block the slot already asked for
+        assert_eq!(next(&mut swarm, &info, 0), Some((0, BLOCK, BLOCK)));
+        assert_eq!(next(&mut swarm, &info, 2), Some((0, 0, BLOCK)));
+        assert_eq!(next(&mut swarm, &info, 0), None);
+
+        // the first copy to arrive cancels the others
+        swarm.add_block(1, 0, BLOCK, &[0; BLOCK_SIZE], &peer(1));
+        assert_eq!(swarm.cancels.get(&0), Some(&vec![(0, BLOCK)]));
+        assert!(!swarm.cancels.contains_key(&1));
+    }
+
+    #[test]
+    fn a_bad_piece_from_one_peer_blames_it() {
+        let info = info(1);
+        let mut swarm = Swarm::new(&info);
+
+        next(&mut swarm, &info, 0);
+        next(&mut swarm, &info, 0);
+        let (data, senders) = deliver(&mut swarm, 0, 0, [1, 2], &peer(1)).unwrap();
+
+        assert_eq!(swarm.finish_piece(0, &data, senders, false), [peer(1)]);
+        assert!(swarm.failed.is_empty());
+        assert_eq!(next(&mut swarm, &info, 1), Some((0, 0, BLOCK)));
+    }
+
+    #[test]
+    fn a_bad_piece_from_several_peers_blames_those_whose_blocks_differ() {
+        let info = info(1);
+        let mut swarm = Swarm::new(&info);
+
+        next(&mut swarm, &info, 0);
+        next(&mut swarm, &info, 1);
+        swarm.add_block(0, 0, 0, &[1; BLOCK_SIZE], &peer(1));
+        let (data, senders) = swarm
+            .add_block(1, 0, BLOCK, &[9; BLOCK_SIZE], &peer(2))
+            .1
+            .unwrap();
+        assert!(swarm.finish_piece(0, &data, senders, false).is_empty());
+
+        // the retry belongs to the first slot that picks it up
+        assert_eq!(next(&mut swarm, &info, 2), Some((0, 0, BLOCK)));
+        assert_eq!(next(&mut swarm, &info, 3), None);
+        assert_eq!(next(&mut swarm, &info, 2), Some((0, BLOCK, BLOCK)));
+
+        let (data, senders) = deliver(&mut swarm, 2, 0, [1, 2], &peer(3)).unwrap();
+        assert_eq!(swarm.finish_piece(0, &data, senders, true), [peer(2)]);
+        assert!(swarm.finished);
+    }
+
+    #[test]
+    fn abandoned_retries_start_over_for_another_slot() {
+        let info = info(1);
+        let mut swarm = Swarm::new(&info);
+
+        next(&mut swarm, &info, 0);
+        next(&mut swarm, &info, 1);
+        swarm.add_block(0, 0, 0, &[1; BLOCK_SIZE], &peer(1));
+        let (data, senders) = swarm
+            .add_block(1, 0, BLOCK, &[2; BLOCK_SIZE], &peer(2))
+            .1
+            .unwrap();
+        swarm.finish_piece(0, &data, senders, false);
+
+        next(&mut swarm, &info, 2);
+        swarm.add_block(2, 0, 0, &[1; BLOCK_SIZE], &peer(3));
+        // blocks from other slots are ignored while it has an owner
+        let late = swarm.add_block(3, 0, BLOCK, &[2; BLOCK_SIZE], &peer(4));
+        assert!(late.1.is_none());
+
+        swarm.abandon(2);
+        assert_eq!(next(&mut swarm, &info, 3), Some((0, 0, BLOCK)));
+        assert_eq!(swarm.unstarted, 0);
+    }
+}
@@ -0,0 +1,321 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_bytes::ByteBuf;
+
+use super::message::Message;
+use super::pipeline::DEFAULT_MAX_IN_FLIGHT;
+use crate::bencode_decode::BenValue;
+use crate::bencode_stream::{DecodeLimits, Decoded, StreamDecoder};
+use crate::{bencode_de, bencode_ser};
+
+/// Extended message id of the extension handshake itself.
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+/// The bencoded extended handshake (BEP 10).
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    /// Extension names mapped to the ids the sender wants them sent with.
+    #[serde(default, deserialize_with = "extension_ids")]
+    pub m: BTreeMap<String, i64>,
+    /// Client name and version, not necessarily UTF-8.
+    pub v: Option<ByteBuf>,
+    /// Listening port of the sender.
+    pub p: Option<i64>,
+    /// Outstanding requests the sender will queue.
+    pub reqq: Option<i64>,
+    /// Size of the info dictionary, for ut_metadata.
+    pub metadata_size: Option<i64>,
+    /// Our address as seen by the sender, 4 or 16 bytes.
+    pub yourip: Option<ByteBuf>,
+}
+
+// entries we couldn't use anyway, with non-UTF-8 names or non-integer ids,
+// are skipped rather than failing the whole handshake
+fn extension_ids<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, i64>, D::Error> {
+    let entries = BTreeMap::<ByteBuf, BenValue>::deserialize(deserializer)?;
+
+    Ok(entries
+        .into_iter()
+        .filter_map(
+            |(name, id)| match (String::from_utf8(name.into_vec()), id) {
+                (Ok(name), BenValue::Int(id)) => Some((name, id)),
+                _ => None,
+            },
+        )
+        .collect())
+}
+
+/// A protocol extension spoken over `Extended` messages.
+///
+/// Handlers hand back payloads to send to the peer; the registry prefixes
+/// them with the id the peer assigned to the extension.
+pub trait ExtensionHandler: Send {
+    /// Name in the `m` dictionary, e.g. `ut_metadata`.
+    fn name(&self) -> &'static str;
+
+    /// Adds this extension's keys to our extended handshake.
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// Called when the peer's extended handshake advertises this extension.
+    fn on_handshake(&mut self, _handshake: &ExtendedHandshake) -> Result<Vec<Vec<u8>>, Error> {
+        Ok(Vec::new())
+    }
+
+    /// Called for each message the peer sends with our id for this extension.
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, Error>;
+
+    /// Called regularly while the connection is open, for extensions that
+    /// send on their own schedule.
+    fn poll(&mut self) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+/// Handlers we support, keyed by the ids we assign, and the ids the peer
+/// assigned in its extended handshake.
+///
+/// A handler that fails is turned off for the rest of the connection
+/// instead of dropping it; `failure` tells why.
+#[derive(Default)]
+pub struct ExtensionRegistry {
+    handlers: Vec<Box<dyn ExtensionHandler>>,
+    peer_ids: BTreeMap<String, u8>,
+    peer_handshake: Option<ExtendedHandshake>,
+    failures: BTreeMap<&'static str, Error>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> ExtensionRegistry {
+        ExtensionRegistry::default()
+    }
+
+    /// Registers a handler and returns the id the peer should use for it.
+    pub fn register(&mut self, handler: Box<dyn ExtensionHandler>) -> u8 {
+        self.handlers.push(handler);
+        self.handlers.len() as u8
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    /// The first registered handler of type `T`.
+    pub fn handler_mut<T: ExtensionHandler + 'static>(&mut self) -> Option<&mut T> {
+        self.handlers
+            .iter_mut()
+            .find_map(|handler| handler.as_any_mut().downcast_mut::<T>())
+    }
+
+    /// The id the peer wants `name` messages sent with, once its extended
+    /// handshake has arrived.
+    pub fn peer_id(&self, name: &str) -> Option<u8> {
+        self.peer_ids.get(name).copied()
+    }
+
+    pub fn peer_handshake(&self) -> Option<&ExtendedHandshake> {
+        self.peer_handshake.as_ref()
+    }
+
+    /// Why the extension `name` was turned off, if it was.
+    pub fn failure(&self, name: &str) -> Option<&Error> {
+        self.failures.get(name)
+    }
+
+    /// Our extended handshake, telling the peer its own address when known.
+    pub fn handshake_message(&self, peer_ip: Option<IpAddr>) -> Message {
+        let mut handshake = ExtendedHandshake {
+            v: Some(ByteBuf::from(format!(
+                "{} {}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            ))),
+            yourip: peer_ip.map(|ip| {
+                ByteBuf::from(match ip {
+                    IpAddr::V4(ip) => ip.octets().to_vec(),
+                    IpAddr::V6(ip) => ip.octets().to_vec(),
+                })
+            }),
+            // we queue as many of the peer's requests as we pipeline ourselves
+            reqq: Some(DEFAULT_MAX_IN_FLIGHT as i64),
+            ..ExtendedHandshake::default()
+        };
+
+        for (index, handler) in self.handlers.iter().enumerate() {
+            handshake
+                .m
+                .insert(handler.name().to_string(), index as i64 + 1);
+            handler.extend_handshake(&mut handshake);
+        }
+
+        let payload = bencode_ser::to_bytes(&handshake).expect("Failed to encode handshake");
+        Message::Extended {
+            id: EXTENDED_HANDSHAKE_ID,
+            payload,
+        }
+    }
+
+    /// Routes an `Extended` message to its handler and returns the
+    /// messages to send back.
+    pub fn handle(&mut self, id: u8, body: &[u8]) -> Vec<Message> {
+        if id == EXTENDED_HANDSHAKE_ID {
+            return self.handle_handshake(body);
+        }
+
+        // ids we never assigned are ignored, as BEP 10 asks
+        let Some(handler) = self.handlers.get_mut(id as usize - 1) else {
+            return Vec::new();
+        };
+
+        let name = handler.name();
+        if self.failures.contains_key(name) {
+            return Vec::new();
+        }
+
+        match handler.on_message(body) {
+            Ok(replies) => self.to_messages(name, replies),
+            Err(err) => {
+                self.failures.insert(name, err);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Messages extensions want to send unprompted, once the peer's
+    /// extended handshake has told us their ids.
+    pub fn poll(&mut self) -> Vec<Message> {
+        if self.peer_handshake.is_none() {
+            return Vec::new();
+        }
+
+        let mut messages = Vec::new();
+        for index in 0..self.handlers.len() {
+            let name = self.handlers[index].name();
+            if self.peer_ids.contains_key(name) && !self.failures.contains_key(name) {
+                let payloads = self.handlers[index].poll();
+                messages.extend(self.to_messages(name, payloads));
+            }
+        }
+
+        messages
+    }
+
+    fn handle_handshake(&mut self, body: &[u8]) -> Vec<Message> {
+        let handshake = match split_payload(body)
+            .and_then(|(dict, _)| Ok(bencode_de::from_bytes::<ExtendedHandshake>(dict)?))
+        {
+            Ok(handshake) => handshake,
+            Err(err) => {
+                // without ids from the peer no extension can be spoken
+                for handler in &self.handlers {
+                    let err = Error::new(err.kind(), format!("Bad extended handshake: {}", err));
+                    self.failures.entry(handler.name()).or_insert(err);
+                }
+                return Vec::new();
+            }
+        };
+
+        // a later handshake may change ids, and id 0 turns an extension off
+        for (name, &id) in &handshake.m {
+            match u8::try_from(id) {
+                Ok(id) if id != 0 => self.peer_ids.insert(name.clone(), id),
+                _ => self.peer_ids.remove(name),
+            };
+        }
+
+        let mut messages = Vec::new();
+        for index in 0..self.handlers.len() {
+            let name = self.handlers[index].name();
+            if !self.peer_ids.contains_key(name) || self.failures.contains_key(name) {
+                continue;
+            }
+
+            match self.handlers[index].on_handshake(&handshake) {
+                Ok(replies) => messages.extend(self.to_messages(name, replies)),
+                Err(err) => {
+                    self.failures.insert(name, err);
+                }
+            }
+        }
+
+        self.peer_handshake = Some(handshake);
+
+        messages
+    }
+
+    fn to_messages(&self, name: &str, payloads: Vec<Vec<u8>>) -> Vec<Message> {
+        match self.peer_id(name) {
+            Some(id) => payloads
+                .into_iter()
+                .map(|payload| Message::Extended { id, payload })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Splits an extension payload into its bencoded dictionary and the raw
+/// data after it, checking the dictionary against the decode limits.
+pub fn split_payload(payload: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    let mut decoder = StreamDecoder::new(DecodeLimits::default());
+    decoder.push(payload);
+
+    match decoder.next_value()? {
+        Decoded::Value(_) => Ok(payload.split_at(payload.len() - decoder.remaining().len())),
+        Decoded::NeedMore => Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "Truncated extension message",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::peers::{Peer, PeerPool};
+    use crate::models::pex::PexHandler;
+
+    fn with_pex() -> ExtensionRegistry {
+        let mut registry = ExtensionRegistry::new();
+        let remote = Peer {
+            ip: "10.0.0.1".to_string(),
+            port: 6881,
+        };
+        registry.register(Box::new(PexHandler::new(PeerPool::shared([]), remote)));
+        registry
+    }
+
+    #[test]
+    fn handshake_tolerates_bytes_it_cannot_read() {
+        let mut registry = with_pex();
+        registry.handle(0, b"d1:md6:ut_pexi1e2:\xffxi2e3:fooli1eee1:v3:\xb5Tre");
+
+        let handshake = registry.peer_handshake().unwrap();
+        assert_eq!(handshake.v, Some(ByteBuf::from(b"\xb5Tr".to_vec())));
+        assert_eq!(handshake.m.len(), 1);
+        assert_eq!(registry.peer_id("ut_pex"), Some(1));
+        assert!(registry.failure("ut_pex").is_none());
+    }
+
+    #[test]
+    fn undecodable_messages_turn_the_extension_off() {
+        let mut registry = with_pex();
+        registry.handle(0, b"d1:md6:ut_pexi1eee");
+
+        assert!(registry.handle(1, b"d5:added").is_empty());
+        assert!(registry.failure("ut_pex").is_some());
+        assert!(registry.poll().is_empty());
+
+        let mut registry = with_pex();
+        registry.handle(0, b"li1ee");
+        assert!(registry.peer_handshake().is_none());
+        assert!(registry.failure("ut_pex").is_some());
+    }
+}
@@ -1,13 +1,28 @@
 use std::{
-    io::{Read, Write},
-    net::TcpStream,
+    collections::{HashSet, VecDeque},
+    fmt,
+    io::{Error, ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
-use crate::models::peers::{PeerMessage, PeerMessageType};
+use sha1::{Digest, Sha1};
 
+use super::extension::ExtensionRegistry;
 use super::info::MetaInfo;
-
-const KB_16: usize = 16 * 1024;
+use super::message::{Message, MAX_MESSAGE_LENGTH};
+use super::pipeline::{PeerRequests, RequestUpdate, BLOCK_SIZE, DEFAULT_MAX_IN_FLIGHT};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// how long a peer may stay silent before we give up on it
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+// reserved byte and bit advertising the extension protocol (BEP 10)
+const EXTENSION_BYTE: usize = 5;
+const EXTENSION_BIT: u8 = 0x10;
+// and the fast extension (BEP 6)
+const FAST_BYTE: usize = 7;
+const FAST_BIT: u8 = 0x04;
 
 pub struct HandShake {
     pub info_hash: Vec<u8>,
@@ -15,6 +30,32 @@ pub struct HandShake {
     pub port: u16,
     pub peer_id: String,
     pub socket: Option<TcpStream>,
+    // the socket's write half; every message goes out through it so that
+    // other threads holding a clone can write whole messages in between
+    writer: Option<PeerWriter>,
+    /// Reserved bytes we send; these advertise the extensions we support.
+    pub reserved: [u8; 8],
+    /// Reserved bytes from the peer's handshake, zero until connected.
+    pub peer_reserved: [u8; 8],
+    /// Handlers for `Extended` messages, used once both sides set the
+    /// extension bit.
+    pub extensions: ExtensionRegistry,
+    /// Whether the peer is choking us.
+    pub choked: bool,
+    /// Pieces the peer has, from its bitfield and have messages.
+    pub peer_bitfield: Vec<u8>,
+    /// Pieces in the torrent, when known; piece indexes from the peer are
+    /// checked against it.
+    pub piece_count: Option<usize>,
+    /// Set by have-all; the bitfield is then ignored.
+    pub peer_has_all: bool,
+    /// Pieces we may request even while choked (BEP 6).
+    pub allowed_fast: HashSet<u32>,
+    /// Pieces the peer suggested we download, oldest first.
+    pub suggested: Vec<u32>,
+    /// Most block requests kept outstanding at once; the window adapts
+    /// below this to the connection's bandwidth-delay product.
+    pub max_in_flight: usize,
 }
 
 impl HandShake {
@@ -25,13 +66,56 @@ impl HandShake {
             port,
             peer_id: peer_id.to_string(),
             socket: None,
+            writer: None,
+            reserved: [0; 8],
+            peer_reserved: [0; 8],
+            extensions: ExtensionRegistry::new(),
+            choked: true,
+            peer_bitfield: Vec::new(),
+            piece_count: None,
+            peer_has_all: false,
+            allowed_fast: HashSet::new(),
+            suggested: Vec::new(),
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
         }
     }
 
+    pub fn enable_extensions(&mut self) {
+        self.reserved[EXTENSION_BYTE] |= EXTENSION_BIT;
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.peer_reserved[EXTENSION_BYTE] & EXTENSION_BIT != 0
+    }
+
+    fn extensions_enabled(&self) -> bool {
+        self.reserved[EXTENSION_BYTE] & EXTENSION_BIT != 0 && self.supports_extensions()
+    }
+
+    pub fn enable_fast(&mut self) {
+        self.reserved[FAST_BYTE] |= FAST_BIT;
+    }
+
+    pub fn supports_fast(&self) -> bool {
+        self.peer_reserved[FAST_BYTE] & FAST_BIT != 0
+    }
+
+    pub fn fast_enabled(&self) -> bool {
+        self.reserved[FAST_BYTE] & FAST_BIT != 0 && self.supports_fast()
+    }
+
+    pub fn peer_has(&self, piece_index: usize) -> bool {
+        self.peer_has_all
+            || self
+                .peer_bitfield
+                .get(piece_index / 8)
+                .is_some_and(|byte| byte & (0x80 >> (piece_index % 8)) != 0)
+    }
+
     pub fn get_handshake(&self) -> Vec<u8> {
         let mut handshake = vec![19];
         handshake.extend(b"BitTorrent protocol");
-        handshake.extend(vec![0; 8]);
+        handshake.extend(self.reserved);
         handshake.extend(self.info_hash.clone());
         handshake.extend(self.peer_id.clone().into_bytes());
 
@@ -40,122 +124,397 @@ impl HandShake {
 
     // perform handshake and return peer id
     pub fn perform_handshake(&mut self) -> Vec<u8> {
-        let mut stream = TcpStream::connect(format!("{}:{}", self.addr, self.port))
-            .expect("Failed to connect to peer");
+        self.try_handshake().expect("Failed to handshake with peer")
+    }
 
-        let handshake = self.get_handshake();
+    // like perform_handshake, but lets the caller move on to another peer
+    pub fn try_handshake(&mut self) -> Result<Vec<u8>, Error> {
+        let addr = (self.addr.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Peer address did not resolve"))?;
+        let mut stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
 
-        stream
-            .write_all(&handshake)
-            .expect("Failed to write to stream");
+        let handshake = self.get_handshake();
+        stream.write_all(&handshake)?;
 
         // read handshake response
         let mut response = vec![0; 68];
-        stream
-            .read_exact(&mut response)
-            .expect("Failed to read from stream");
+        stream.read_exact(&mut response)?;
+
+        if response[28..48] != self.info_hash[..] {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Peer answered with a different info hash",
+            ));
+        }
 
+        self.peer_reserved.copy_from_slice(&response[20..28]);
+        self.writer = Some(PeerWriter::new(stream.try_clone()?));
         self.socket = Some(stream);
 
+        // peer state doesn't carry over between connections
+        self.choked = true;
+        self.peer_bitfield.clear();
+        self.peer_has_all = false;
+        self.allowed_fast.clear();
+        self.suggested.clear();
+
+        if self.extensions_enabled() {
+            let message = self.extensions.handshake_message(Some(addr.ip()));
+            self.send_message(&message)?;
+        }
+
         // return peer id
-        response[response.len() - 20..].to_vec()
+        Ok(response[response.len() - 20..].to_vec())
     }
 
-    pub fn download_piece(&mut self, piece_index: usize, meta_info: &MetaInfo) -> Vec<u8> {
-        if self.socket.is_none() {
-            self.perform_handshake();
+    pub fn send_message(&mut self, message: &Message) -> Result<(), Error> {
+        self.writer
+            .as_ref()
+            .ok_or_else(not_connected)?
+            .send(message)
+    }
+
+    /// A handle for sending messages to the peer from another thread.
+    pub fn writer(&self) -> Option<PeerWriter> {
+        self.writer.clone()
+    }
+
+    /// Waits up to `timeout` for the peer to send something, without
+    /// reading it, and returns whether there is anything to read.
+    pub fn wait_readable(&mut self, timeout: Duration) -> Result<bool, Error> {
+        let stream = self.socket.as_ref().ok_or_else(not_connected)?;
+        let read_timeout = stream.read_timeout()?;
+
+        stream.set_read_timeout(Some(timeout))?;
+        let peeked = stream.peek(&mut [0; 1]);
+        stream.set_read_timeout(read_timeout)?;
+
+        match peeked {
+            // a closed connection counts too; reading it reports the error
+            Ok(_) => Ok(true),
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                Ok(false)
+            }
+            Err(err) => Err(err),
         }
+    }
 
-        let mut stream = match &mut self.socket {
-            Some(stream) => stream,
-            None => panic!("No socket"),
-        };
+    /// Reads the next message from the peer. `Extended` messages are first
+    /// passed to the registered extensions and their replies sent, along
+    /// with anything the extensions queued in the meantime.
+    pub fn read_message(&mut self) -> Result<Message, Error> {
+        let extensions_enabled = self.extensions_enabled();
+        let fast_enabled = self.fast_enabled();
+        let stream = self.socket.as_mut().ok_or_else(not_connected)?;
+        let message = Message::read_from(stream, MAX_MESSAGE_LENGTH)?;
+
+        // we never unchoke anyone, so with the fast extension their
+        // requests get an explicit reject instead of silence
+        if let Message::Request {
+            index,
+            begin,
+            length,
+        } = message
+        {
+            if fast_enabled {
+                let reject = Message::RejectRequest {
+                    index,
+                    begin,
+                    length,
+                };
+                self.send_message(&reject)?;
+            }
+        }
 
-        // wait for bitfield message
-        let message = PeerMessage::from_socket(&mut stream).expect("Failed to read from stream");
+        if extensions_enabled {
+            let mut replies = Vec::new();
+            if let Message::Extended { id, payload } = &message {
+                replies = self.extensions.handle(*id, payload);
+            }
+            replies.extend(self.extensions.poll());
 
-        if message.message_type != PeerMessageType::BitField {
-            panic!("Expected bitfield message");
+            for reply in replies {
+                self.send_message(&reply)?;
+            }
         }
 
-        // send interested message
-        let interested_message = PeerMessage {
-            length: 1,
-            message_type: PeerMessageType::Interested,
-            payload: vec![],
-        };
+        self.track_peer_state(&message);
 
-        stream
-            .write_all(&interested_message.to_bytes())
-            .expect("Failed to write to stream");
+        Ok(message)
+    }
 
-        // wait for unchoke message
-        let message = PeerMessage::from_socket(&mut stream).expect("Failed to read from stream");
+    fn track_peer_state(&mut self, message: &Message) {
+        match message {
+            Message::Choke => self.choked = true,
+            Message::Unchoke => self.choked = false,
+            Message::BitField(bitfield) => {
+                let len = bitfield.len().min(self.bitfield_len());
+                self.peer_bitfield = bitfield[..len].to_vec();
+            }
+            Message::HaveAll => self.peer_has_all = true,
+            Message::HaveNone => {
+                self.peer_has_all = false;
+                self.peer_bitfield.clear();
+            }
+            Message::Have(index) if self.is_piece(*index) => {
+                let byte = *index as usize / 8;
+                if self.peer_bitfield.len() <= byte {
+                    self.peer_bitfield.resize(byte + 1, 0);
+                }
+                self.peer_bitfield[byte] |= 0x80 >> (index % 8);
+            }
+            Message::AllowedFast(index) if self.is_piece(*index) => {
+                self.allowed_fast.insert(*index);
+            }
+            Message::SuggestPiece(index) if self.is_piece(*index) => self.suggested.push(*index),
+            _ => {}
+        }
+    }
 
-        if message.message_type != PeerMessageType::Unchoke {
-            panic!("Expected unchoke message");
+    // without a piece count, a bitfield message is as far as indexes can go
+    fn piece_limit(&self) -> usize {
+        self.piece_count.unwrap_or(MAX_MESSAGE_LENGTH as usize * 8)
+    }
+
+    fn is_piece(&self, index: u32) -> bool {
+        (index as usize) < self.piece_limit()
+    }
+
+    // bytes of a bitfield with a bit for every piece
+    fn bitfield_len(&self) -> usize {
+        match self.piece_limit() {
+            0 => 0,
+            pieces => (pieces - 1) / 8 + 1,
         }
+    }
 
-        // send request message
-        let mut offset = 0;
-        let file_length = meta_info.info.length;
-        let piece_length = meta_info.info.piece_length;
-        let length = (file_length as usize - (piece_index * piece_length as usize))
-            .min(piece_length as usize) as usize;
-        let mut chunks: Vec<u8> = Vec::new();
-
-        while offset < length {
-            let block_length = KB_16.min(length - offset);
-            let mut payload: Vec<u8> = Vec::new();
-            payload.extend((piece_index as u32).to_be_bytes());
-            payload.extend((offset as u32).to_be_bytes());
-            payload.extend((block_length as u32).to_be_bytes());
-
-            let request_message = PeerMessage {
-                length: (payload.len() + 1) as u32,
-                message_type: PeerMessageType::Request,
-                payload,
-            };
-
-            stream
-                .write_all(&request_message.to_bytes())
-                .expect("Failed to write to stream");
-
-            // wait for piece message
-
-            let message =
-                PeerMessage::from_socket(&mut stream).expect("Failed to read from stream");
-
-            if message.message_type != PeerMessageType::Piece {
-                panic!("Expected piece message");
+    /// Downloads one piece and checks it against its hash in `info.pieces`;
+    /// a mismatch is an `InvalidData` error wrapping `HashMismatch`.
+    pub fn download_piece(
+        &mut self,
+        piece_index: usize,
+        meta_info: &MetaInfo,
+    ) -> Result<Vec<u8>, Error> {
+        check_piece_index(meta_info, piece_index)?;
+        if self.socket.is_none() {
+            self.try_handshake()?;
+        }
+
+        // send interested message
+        self.send_message(&Message::Interested)?;
+
+        let length = meta_info.info.piece_size(piece_index);
+        let mut pending: VecDeque<(u32, u32)> = (0..length)
+            .step_by(BLOCK_SIZE)
+            .map(|offset| (offset as u32, BLOCK_SIZE.min(length - offset) as u32))
+            .collect();
+        let mut requests = PeerRequests::new(self.max_in_flight);
+        let mut chunks: Vec<u8> = vec![0; length];
+        let mut received = 0;
+        let index = piece_index as u32;
+
+        while received < length {
+            // allowed-fast pieces can be requested while choked
+            let may_request =
+                self.peer_has(piece_index) && (!self.choked || self.allowed_fast.contains(&index));
+            while may_request && requests.has_room() {
+                let Some(next) = pending
+                    .iter()
+                    .position(|&(begin, _)| !requests.is_rejected(index, begin))
+                else {
+                    break;
+                };
+                let (begin, block_length) = pending.remove(next).expect("Position in range");
+                requests.send(self, index, begin, block_length)?;
+            }
+            // the peer lets us ask, but refused every block still missing
+            if may_request && requests.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("Peer rejected the rest of piece {}", piece_index),
+                ));
             }
 
-            chunks.extend(&message.payload[8..]);
-            offset += block_length;
+            let message = self.read_message()?;
+
+            match requests.on_message(&message, self)? {
+                RequestUpdate::Block { begin, data, .. } => {
+                    let offset = begin as usize;
+                    chunks[offset..offset + data.len()].copy_from_slice(data);
+                    received += data.len();
+                }
+                RequestUpdate::Dropped(blocks) => {
+                    for (_, begin, block_length) in blocks.into_iter().rev() {
+                        pending.push_front((begin, block_length));
+                    }
+                }
+                RequestUpdate::None => {}
+            }
 
-            if offset == length {
-                break;
+            if matches!(message, Message::BitField(_) | Message::HaveNone)
+                && !self.peer_has(piece_index)
+            {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("Peer does not have piece {}", piece_index),
+                ));
             }
         }
 
-        _ = match &mut self.socket {
-            Some(stream) => stream.shutdown(std::net::Shutdown::Both),
-            None => panic!("No socket"),
-        };
+        self.writer = None;
+        if let Some(stream) = self.socket.take() {
+            _ = stream.shutdown(std::net::Shutdown::Both);
+        }
 
-        self.socket = None;
+        if Sha1::digest(&chunks)[..] != *meta_info.info.piece_hash(piece_index) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                HashMismatch { piece_index },
+            ));
+        }
 
-        chunks
+        Ok(chunks)
     }
+}
 
-    pub fn download_all_pieces(&mut self, meta_info: MetaInfo) -> Vec<u8> {
-        let mut pieces: Vec<u8> = Vec::new();
+/// The write half of a peer connection, shared between threads.
+///
+/// Each message is written whole under a lock, so messages sent from
+/// different threads never interleave on the wire.
+#[derive(Clone)]
+pub struct PeerWriter(Arc<Mutex<TcpStream>>);
 
-        for i in 0..meta_info.info.pieces.len() / 20 {
-            let mut piece = self.download_piece(i, &meta_info);
-            pieces.append(&mut piece);
-        }
+impl PeerWriter {
+    fn new(stream: TcpStream) -> PeerWriter {
+        PeerWriter(Arc::new(Mutex::new(stream)))
+    }
+
+    pub fn send(&self, message: &Message) -> Result<(), Error> {
+        let mut stream = self.0.lock().expect("Peer writer poisoned");
+        message.write_to(&mut *stream)
+    }
+}
+
+/// A downloaded piece whose SHA-1 differs from the one in the torrent.
+#[derive(Debug)]
+pub struct HashMismatch {
+    pub piece_index: usize,
+}
+
+impl HashMismatch {
+    /// Whether `err` is a hash mismatch rather than a connection problem.
+    pub fn is(err: &Error) -> bool {
+        err.get_ref()
+            .is_some_and(|inner| inner.is::<HashMismatch>())
+    }
+}
+
+impl fmt::Display for HashMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Piece {} failed its hash check", self.piece_index)
+    }
+}
+
+impl std::error::Error for HashMismatch {}
+
+/// Fails with `InvalidInput` unless the torrent has a piece `piece_index`.
+pub fn check_piece_index(meta_info: &MetaInfo, piece_index: usize) -> Result<(), Error> {
+    let piece_count = meta_info.info.piece_count();
+    if piece_index >= piece_count {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Piece {} is out of range, the torrent has {} pieces",
+                piece_index, piece_count
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+fn not_connected() -> Error {
+    Error::new(ErrorKind::NotConnected, "Not connected to the peer")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn piece_indexes_past_the_torrent_are_ignored() {
+        let mut handshake = HandShake::new(&[0; 20], "127.0.0.1", 6881, "00112233445566778899");
+        handshake.piece_count = Some(10);
+
+        handshake.track_peer_state(&Message::Have(u32::MAX));
+        handshake.track_peer_state(&Message::Have(10));
+        handshake.track_peer_state(&Message::AllowedFast(10));
+        assert!(handshake.peer_bitfield.is_empty());
+        assert!(handshake.allowed_fast.is_empty());
+
+        handshake.track_peer_state(&Message::Have(9));
+        assert_eq!(handshake.peer_bitfield, vec![0, 0x40]);
+
+        handshake.track_peer_state(&Message::BitField(vec![0xff; 1000]));
+        assert_eq!(handshake.peer_bitfield.len(), 2);
+    }
+
+    #[test]
+    fn rejected_blocks_are_not_requested_again() {
+        // one piece of two blocks
+        let mut torrent =
+            b"d4:infod6:lengthi32768e4:name1:f12:piece lengthi32768e6:pieces20:".to_vec();
+        torrent.extend([0; 20]);
+        torrent.extend(b"ee");
+        let meta_info = MetaInfo::from_bytes(&torrent).unwrap();
+        let info_hash = meta_info.info_hash();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let peer_hash = info_hash.clone();
+        // a fast-extension peer that unchokes us and rejects every request
+        let peer = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut handshake = [0; 68];
+            stream.read_exact(&mut handshake).unwrap();
+            handshake[FAST_BYTE] |= FAST_BIT;
+            handshake[28..48].copy_from_slice(&peer_hash);
+            stream.write_all(&handshake).unwrap();
+            Message::HaveAll.write_to(&mut stream).unwrap();
+            Message::Unchoke.write_to(&mut stream).unwrap();
+
+            let mut requests = 0;
+            while let Ok(message) = Message::read_from(&mut stream, MAX_MESSAGE_LENGTH) {
+                if let Message::Request {
+                    index,
+                    begin,
+                    length,
+                } = message
+                {
+                    requests += 1;
+                    let reject = Message::RejectRequest {
+                        index,
+                        begin,
+                        length,
+                    };
+                    reject.write_to(&mut stream).unwrap();
+                }
+            }
+            requests
+        });
+
+        let mut handshake = HandShake::new(&info_hash, "127.0.0.1", port, "00112233445566778899");
+        handshake.enable_fast();
+        let err = handshake.download_piece(0, &meta_info).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
 
-        pieces
+        drop(handshake);
+        assert_eq!(peer.join().unwrap(), 2);
     }
 }
@@ -0,0 +1,186 @@
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use sha1::{Digest, Sha1};
+
+use super::storage::StorageFile;
+
+const READ_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+pub struct HashProgress {
+    pub pieces_done: usize,
+    pub bytes_done: u64,
+    pub elapsed: Duration,
+}
+
+impl HashProgress {
+    /// Bytes hashed per second so far.
+    pub fn throughput(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds == 0.0 {
+            return 0.0;
+        }
+
+        self.bytes_done as f64 / seconds
+    }
+}
+
+/// Hashes the pieces of a payload on a pool of worker threads.
+///
+/// One thread reads the files back to back with large sequential reads and
+/// hands complete pieces to the workers; results are collected on the
+/// calling thread, which also reports progress.
+pub struct PieceHasher {
+    workers: usize,
+}
+
+impl PieceHasher {
+    pub fn new(workers: usize) -> PieceHasher {
+        PieceHasher {
+            workers: workers.max(1),
+        }
+    }
+
+    /// One worker per available CPU.
+    pub fn with_available_parallelism() -> PieceHasher {
+        PieceHasher::new(thread::available_parallelism().map_or(1, |count| count.get()))
+    }
+
+    /// Returns the SHA-1 of every piece, or `None` for pieces that could not
+    /// be read in full because a file is missing or shorter than expected.
+    pub fn hash_files(
+        &self,
+        files: &[StorageFile],
+        piece_length: u64,
+        mut progress: impl FnMut(&HashProgress),
+    ) -> Result<Vec<Option<[u8; 20]>>, Error> {
+        let started = Instant::now();
+        let total_length: u64 = files.iter().map(|file| file.length).sum();
+        let piece_count = match total_length {
+            0 => 0,
+            _ => ((total_length - 1) / piece_length + 1) as usize,
+        };
+        let mut hashes = vec![None; piece_count];
+
+        thread::scope(|scope| {
+            // bounded so the reader can't run far ahead of the workers
+            let (job_sender, job_receiver) = mpsc::sync_channel(self.workers * 2);
+            let (result_sender, result_receiver) = mpsc::channel();
+            let job_receiver = Arc::new(Mutex::new(job_receiver));
+
+            let reader = scope.spawn(move || read_pieces(files, piece_length, job_sender));
+
+            for _ in 0..self.workers {
+                let job_receiver = Arc::clone(&job_receiver);
+                let result_sender = result_sender.clone();
+                scope.spawn(move || hash_worker(&job_receiver, result_sender));
+            }
+            drop(result_sender);
+
+            let mut report = HashProgress {
+                pieces_done: 0,
+                bytes_done: 0,
+                elapsed: Duration::ZERO,
+            };
+            for (index, length, hash) in result_receiver {
+                hashes[index] = hash;
+
+                report.pieces_done += 1;
+                report.bytes_done += length as u64;
+                report.elapsed = started.elapsed();
+                progress(&report);
+            }
+
+            reader.join().expect("Reader thread panicked")
+        })?;
+
+        Ok(hashes)
+    }
+}
+
+type Job = (usize, usize, Option<Vec<u8>>);
+
+fn hash_worker(
+    jobs: &Mutex<Receiver<Job>>,
+    results: mpsc::Sender<(usize, usize, Option<[u8; 20]>)>,
+) {
+    loop {
+        let job = jobs.lock().unwrap().recv();
+        let Ok((index, length, data)) = job else {
+            break;
+        };
+
+        let hash = data.map(|data| Sha1::digest(data).into());
+        if results.send((index, length, hash)).is_err() {
+            break;
+        }
+    }
+}
+
+// reads the files back to back and cuts the stream into pieces; pieces
+// covering missing or short files are sent without data
+fn read_pieces(
+    files: &[StorageFile],
+    piece_length: u64,
+    jobs: mpsc::SyncSender<Job>,
+) -> Result<(), Error> {
+    let mut index = 0;
+    let mut piece = Vec::with_capacity(piece_length as usize);
+    let mut complete = true;
+
+    let mut send = |piece: &mut Vec<u8>, complete: &mut bool| {
+        let length = piece.len();
+        let data = if *complete {
+            Some(std::mem::replace(
+                piece,
+                Vec::with_capacity(piece_length as usize),
+            ))
+        } else {
+            piece.clear();
+            None
+        };
+        *complete = true;
+
+        // the workers only hang up early if the collector has gone away
+        let _ = jobs.send((index, length, data));
+        index += 1;
+    };
+
+    for file in files {
+        let mut reader = match File::open(&file.path) {
+            Ok(handle) => Some(BufReader::with_capacity(READ_BUFFER_SIZE, handle)),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+        let mut remaining = file.length;
+
+        while remaining > 0 {
+            let wanted = (piece_length - piece.len() as u64).min(remaining);
+            let read = match &mut reader {
+                Some(reader) => reader.take(wanted).read_to_end(&mut piece)? as u64,
+                None => 0,
+            };
+
+            if read < wanted {
+                piece.resize(piece.len() + (wanted - read) as usize, 0);
+                complete = false;
+                reader = None;
+            }
+            remaining -= wanted;
+
+            if piece.len() as u64 == piece_length {
+                send(&mut piece, &mut complete);
+            }
+        }
+    }
+
+    if !piece.is_empty() {
+        send(&mut piece, &mut complete);
+    }
+
+    Ok(())
+}
@@ -1,7 +1,10 @@
+use std::collections::BTreeMap;
 use std::fs;
+use std::io::{Error, ErrorKind};
 use std::path::Path;
 
 use serde::Deserialize;
+use serde::Deserializer;
 use serde::Serialize;
 use serde_bytes::ByteBuf;
 
@@ -9,16 +12,48 @@ extern crate sha1;
 
 use sha1::{Digest, Sha1};
 
+use crate::bencode_decode::{decode_bencoded_ref, BenValue};
+use crate::{bencode_de, bencode_ser};
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MetaInfo {
+    /// Empty for trackerless torrents, which then have no `announce` key.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub announce: String,
+    #[serde(rename = "announce-list")]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    /// Free text fields are kept as bytes: clients don't always write UTF-8.
+    pub comment: Option<ByteBuf>,
+    #[serde(rename = "created by")]
+    pub created_by: Option<ByteBuf>,
+    #[serde(rename = "creation date")]
+    pub creation_date: Option<i64>,
+    pub encoding: Option<ByteBuf>,
+    #[serde(rename = "url-list", default, deserialize_with = "string_or_list")]
+    pub url_list: Option<Vec<String>>,
+    pub httpseeds: Option<Vec<String>>,
+    /// DHT bootstrap nodes as `(host, port)`.
+    pub nodes: Option<Vec<(String, i64)>>,
     pub info: Info,
+    /// Keys this struct doesn't model, kept so nothing is lost on rewrite.
+    /// Keys are raw bytes since bencode doesn't require them to be UTF-8.
+    #[serde(flatten)]
+    pub extra: BTreeMap<ByteBuf, BenValue>,
+    /// SHA-1 of the `info` dictionary exactly as it appeared in the file.
+    #[serde(skip)]
+    pub raw_info_hash: Option<[u8; 20]>,
 }
 
 impl MetaInfo {
+    // hash of the original info bytes when parsed from a file, so fields
+    // `Info` doesn't model still count; otherwise hash the re-encoded struct
     pub fn info_hash(&self) -> Vec<u8> {
-        let serialized = serde_bencode::ser::to_bytes(&self.info).unwrap();
+        if let Some(hash) = self.raw_info_hash {
+            return hash.to_vec();
+        }
+
+        let serialized = bencode_ser::to_bytes(&self.info).unwrap();
         let mut hasher = Sha1::new();
         hasher.update(&serialized);
         let result = hasher.finalize();
@@ -26,27 +61,232 @@ impl MetaInfo {
         result.to_vec()
     }
 
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bencode_ser::to_bytes(self).expect("Error encoding torrent")
+    }
+
     pub fn info_hash_str(&self) -> String {
-        let serialized = serde_bencode::ser::to_bytes(&self.info).unwrap();
-        let mut hasher = Sha1::new();
-        hasher.update(&serialized);
-        let result = hasher.finalize();
+        hex::encode(self.info_hash())
+    }
 
-        format!("{:x}", result)
+    pub fn from_file(path: &str) -> Result<MetaInfo, Error> {
+        let bytes = fs::read(Path::new(path))?;
+        MetaInfo::from_bytes(&bytes)
     }
 
-    pub fn from_file(path: &str) -> MetaInfo {
-        let bytes = fs::read(Path::new(path)).expect("No file found");
-        serde_bencode::from_bytes::<MetaInfo>(&bytes).expect("Error decoding file")
+    pub fn from_bytes(bytes: &[u8]) -> Result<MetaInfo, Error> {
+        let root = decode_bencoded_ref(bytes)?;
+        let mut meta_info = bencode_de::from_ref::<MetaInfo>(&root)?;
+        meta_info.info.validate()?;
+
+        let raw_info = root
+            .get(b"info")
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Torrent has no info dictionary"))?
+            .raw();
+        meta_info.raw_info_hash = Some(Sha1::digest(raw_info).into());
+
+        Ok(meta_info)
     }
 }
 
+/// The `info` dictionary. Single-file torrents set `length`, multi-file
+/// torrents set `files` and use `name` as the top-level directory.
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Info {
-    pub length: i64,
+    pub length: Option<i64>,
+    pub files: Option<Vec<FileInfo>>,
     pub name: String,
     #[serde(rename = "piece length")]
     pub piece_length: u64,
     pub pieces: ByteBuf,
+    pub private: Option<i64>,
+    pub source: Option<ByteBuf>,
+    pub md5sum: Option<String>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<ByteBuf, BenValue>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct FileInfo {
+    pub length: i64,
+    pub path: Vec<String>,
+    pub md5sum: Option<String>,
+    /// e.g. BEP 47 `attr` and `sha1`, or `path.utf-8`
+    #[serde(flatten)]
+    pub extra: BTreeMap<ByteBuf, BenValue>,
+}
+
+// BEP 19 allows `url-list` to be a single URL instead of a list
+fn string_or_list<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let urls = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(url) => vec![url],
+        OneOrMany::Many(urls) => urls,
+    };
+
+    Ok(Some(
+        urls.into_iter().filter(|url| !url.is_empty()).collect(),
+    ))
+}
+
+impl Info {
+    /// Checks what the rest of the crate relies on: a non-zero piece
+    /// length, whole 20-byte piece hashes, no negative file lengths, and
+    /// exactly as many pieces as the total length needs.
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |message: String| Err(Error::new(ErrorKind::InvalidData, message));
+
+        if self.piece_length == 0 {
+            return invalid("Piece length is zero".to_string());
+        }
+        if !self.pieces.chunks_exact(20).remainder().is_empty() {
+            return invalid(format!(
+                "Piece hashes are {} bytes, not a multiple of 20",
+                self.pieces.len()
+            ));
+        }
+
+        let lengths: Vec<i64> = match (&self.files, self.length) {
+            (Some(files), _) => files.iter().map(|file| file.length).collect(),
+            (None, Some(length)) => vec![length],
+            (None, None) => return invalid("Info has neither a length nor files".to_string()),
+        };
+        let mut total_length: u64 = 0;
+        for length in lengths {
+            let Ok(length) = u64::try_from(length) else {
+                return invalid(format!("File length {} is negative", length));
+            };
+            let Some(total) = total_length.checked_add(length) else {
+                return invalid("Total length is too large".to_string());
+            };
+            total_length = total;
+        }
+
+        let needed = match total_length {
+            0 => 0,
+            _ => (total_length - 1) / self.piece_length + 1,
+        };
+        if self.piece_count() as u64 != needed {
+            return invalid(format!(
+                "Torrent has {} pieces but its length needs {}",
+                self.piece_count(),
+                needed
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    pub fn total_length(&self) -> u64 {
+        match &self.files {
+            Some(files) => files.iter().map(|file| file.length as u64).sum(),
+            None => self.length.unwrap_or(0) as u64,
+        }
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.pieces.len() / 20
+    }
+
+    pub fn piece_hash(&self, piece_index: usize) -> &[u8] {
+        &self.pieces[piece_index * 20..(piece_index + 1) * 20]
+    }
+
+    // every piece is `piece length` long except possibly the last one
+    pub fn piece_size(&self, piece_index: usize) -> usize {
+        let start = piece_index as u64 * self.piece_length;
+        (self.total_length() - start).min(self.piece_length) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_keys_survive_a_rewrite() {
+        let mut torrent = b"d8:announce3:url4:infod5:filesld4:attr1:x6:lengthi3e4:pathl1:aeee\
+4:name1:d12:piece lengthi16384e6:pieces20:"
+            .to_vec();
+        torrent.extend([0; 20]);
+        torrent.extend(b"e2:\xff\xfei1ee");
+
+        let meta_info = MetaInfo::from_bytes(&torrent).unwrap();
+        assert!(meta_info
+            .extra
+            .contains_key(&ByteBuf::from(b"\xff\xfe".to_vec())));
+        let files = meta_info.info.files.as_ref().unwrap();
+        assert!(files[0].extra.contains_key(&ByteBuf::from("attr")));
+
+        assert_eq!(meta_info.to_bytes(), torrent);
+    }
+
+    #[test]
+    fn trackerless_torrent_with_latin1_comment_round_trips() {
+        let mut torrent = b"d7:comment4:caf\xe94:infod6:lengthi3e4:name1:f12:piece lengthi16384e\
+6:pieces20:"
+            .to_vec();
+        torrent.extend([0; 20]);
+        torrent.extend(b"ee");
+
+        let meta_info = MetaInfo::from_bytes(&torrent).unwrap();
+        assert_eq!(meta_info.announce, "");
+        assert_eq!(meta_info.comment, Some(ByteBuf::from(b"caf\xe9".to_vec())));
+
+        assert_eq!(meta_info.to_bytes(), torrent);
+    }
+
+    fn torrent(info: &str, pieces: usize) -> Vec<u8> {
+        let mut torrent = format!("d4:infod{}4:name1:f6:pieces{}:", info, pieces * 20).into_bytes();
+        torrent.extend(vec![0; pieces * 20]);
+        torrent.extend(b"ee");
+        torrent
+    }
+
+    #[test]
+    fn inconsistent_info_is_rejected() {
+        let cases = [
+            // piece length zero
+            torrent("6:lengthi5e12:piece lengthi0e", 1),
+            // more pieces than the length needs
+            torrent("6:lengthi5e12:piece lengthi4e", 3),
+            // fewer
+            torrent("6:lengthi9e12:piece lengthi4e", 2),
+            torrent("5:filesld6:lengthi-8e4:pathl1:aeee12:piece lengthi4e", 0),
+            torrent("12:piece lengthi4e", 0),
+        ];
+
+        for case in cases {
+            let err = MetaInfo::from_bytes(&case).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{}", err);
+        }
+
+        // a partial hash
+        let mut truncated = b"d4:infod6:lengthi5e4:name1:f12:piece lengthi8e6:pieces19:".to_vec();
+        truncated.extend([0; 19]);
+        truncated.extend(b"ee");
+        let err = MetaInfo::from_bytes(&truncated).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        for valid in [
+            torrent("6:lengthi8e12:piece lengthi4e", 2),
+            torrent("6:lengthi9e12:piece lengthi4e", 3),
+            torrent("6:lengthi0e12:piece lengthi4e", 0),
+        ] {
+            MetaInfo::from_bytes(&valid).unwrap();
+        }
+    }
 }
@@ -0,0 +1,322 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use super::info::MetaInfo;
+use super::peers::Peer;
+
+/// A `magnet:?xt=urn:btih:...` link (BEP 9, plus the BEP 53 `so` key).
+pub struct MagnetLink {
+    pub info_hash: [u8; 20],
+    /// `dn`
+    pub display_name: Option<String>,
+    /// `tr`
+    pub trackers: Vec<String>,
+    /// `ws`
+    pub web_seeds: Vec<String>,
+    /// `x.pe`
+    pub peers: Vec<Peer>,
+    /// `so`, file indexes to download
+    pub select_only: Vec<RangeInclusive<usize>>,
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> Result<MagnetLink, String> {
+        let query = uri
+            .strip_prefix("magnet:?")
+            .ok_or_else(|| "Magnet link must start with magnet:?".to_string())?;
+        let params: Vec<(String, String)> =
+            serde_urlencoded::from_str(query).map_err(|err| format!("Invalid query: {}", err))?;
+
+        let mut info_hash = None;
+        let mut magnet = MagnetLink {
+            info_hash: [0; 20],
+            display_name: None,
+            trackers: Vec::new(),
+            web_seeds: Vec::new(),
+            peers: Vec::new(),
+            select_only: Vec::new(),
+        };
+
+        for (key, value) in params {
+            // numbered variants such as `tr.1` are treated like the plain key
+            let key = match key.split_once('.') {
+                Some((base, index)) if index.parse::<u32>().is_ok() => base.to_string(),
+                _ => key,
+            };
+
+            match key.as_str() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_btih(hash)?);
+                    }
+                }
+                "dn" => magnet.display_name = Some(value),
+                "tr" => magnet.trackers.push(value),
+                "ws" => magnet.web_seeds.push(value),
+                "x.pe" => magnet.peers.push(parse_peer(&value)?),
+                "so" => magnet.select_only.extend(parse_select_only(&value)?),
+                _ => {}
+            }
+        }
+
+        magnet.info_hash = info_hash.ok_or_else(|| "No urn:btih info hash".to_string())?;
+
+        Ok(magnet)
+    }
+
+    pub fn from_meta_info(meta_info: &MetaInfo) -> MagnetLink {
+        let mut info_hash = [0; 20];
+        info_hash.copy_from_slice(&meta_info.info_hash());
+
+        let mut trackers: Vec<String> = Vec::new();
+        let tiers = meta_info.announce_list.iter().flatten().flatten();
+        for tracker in std::iter::once(&meta_info.announce).chain(tiers) {
+            if !tracker.is_empty() && !trackers.contains(tracker) {
+                trackers.push(tracker.clone());
+            }
+        }
+
+        MagnetLink {
+            info_hash,
+            display_name: Some(meta_info.info.name.clone()),
+            trackers,
+            web_seeds: meta_info.url_list.clone().unwrap_or_default(),
+            peers: Vec::new(),
+            select_only: Vec::new(),
+        }
+    }
+
+    pub fn info_hash_str(&self) -> String {
+        hex::encode(self.info_hash)
+    }
+}
+
+impl fmt::Display for MagnetLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut params: Vec<(&str, String)> = Vec::new();
+
+        if let Some(name) = &self.display_name {
+            params.push(("dn", name.clone()));
+        }
+        for tracker in &self.trackers {
+            params.push(("tr", tracker.clone()));
+        }
+        for web_seed in &self.web_seeds {
+            params.push(("ws", web_seed.clone()));
+        }
+        for peer in &self.peers {
+            params.push(("x.pe", peer.to_string()));
+        }
+        if !self.select_only.is_empty() {
+            let ranges: Vec<String> = self
+                .select_only
+                .iter()
+                .map(|range| {
+                    if range.start() == range.end() {
+                        range.start().to_string()
+                    } else {
+                        format!("{}-{}", range.start(), range.end())
+                    }
+                })
+                .collect();
+            params.push(("so", ranges.join(",")));
+        }
+
+        write!(f, "magnet:?xt=urn:btih:{}", self.info_hash_str())?;
+        if !params.is_empty() {
+            let query = serde_urlencoded::to_string(&params).map_err(|_| fmt::Error)?;
+            write!(f, "&{}", query)?;
+        }
+
+        Ok(())
+    }
+}
+
+// 40 hex characters, or 32 base32 characters as in older links
+fn parse_btih(hash: &str) -> Result<[u8; 20], String> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).map_err(|err| format!("Invalid hex info hash: {}", err))?,
+        32 => decode_base32(hash).ok_or_else(|| "Invalid base32 info hash".to_string())?,
+        _ => return Err(format!("Info hash has unexpected length {}", hash.len())),
+    };
+
+    let mut info_hash = [0; 20];
+    info_hash.copy_from_slice(&bytes);
+
+    Ok(info_hash)
+}
+
+// RFC 4648 alphabet, case-insensitive, no padding
+fn decode_base32(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer: u64 = 0;
+    let mut bits = 0;
+
+    for c in encoded.bytes() {
+        let quintet = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+
+        buffer = (buffer << 5) | quintet as u64;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+// `host:port`, with IPv6 hosts in brackets
+fn parse_peer(value: &str) -> Result<Peer, String> {
+    let (host, port) = value
+        .rsplit_once(':')
+        .ok_or_else(|| format!("Peer {} has no port", value))?;
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| format!("Peer {} has an invalid port", value))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    Ok(Peer {
+        ip: host.to_string(),
+        port,
+    })
+}
+
+// e.g. `0,2,4-6`
+fn parse_select_only(value: &str) -> Result<Vec<RangeInclusive<usize>>, String> {
+    let invalid = || format!("Invalid file selection {}", value);
+
+    value
+        .split(',')
+        .map(|part| {
+            let (start, end) = part.split_once('-').unwrap_or((part, part));
+            let start = start.parse::<usize>().map_err(|_| invalid())?;
+            let end = end.parse::<usize>().map_err(|_| invalid())?;
+
+            if start > end {
+                return Err(invalid());
+            }
+
+            Ok(start..=end)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+
+    #[test]
+    fn hex_and_base32_hashes_agree() {
+        let hex = parse_btih(HASH).unwrap();
+        assert_eq!(hex::encode(hex), HASH);
+        assert_eq!(parse_btih(&HASH.to_uppercase()).unwrap(), hex);
+
+        assert_eq!(parse_btih("YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap(), hex);
+        assert_eq!(parse_btih("yex6dqdlxisuvhoj6um3gnnkpqjwpkek").unwrap(), hex);
+    }
+
+    #[test]
+    fn bad_hashes_are_rejected() {
+        assert!(parse_btih("").is_err());
+        assert!(parse_btih(&HASH[..39]).is_err());
+        assert!(parse_btih(&HASH.replace('c', "g")).is_err());
+        // 1, 8 and padding aren't in the base32 alphabet
+        assert!(parse_btih("YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKE1").is_err());
+        assert!(parse_btih("YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKE=").is_err());
+    }
+
+    #[test]
+    fn base32_decodes_rfc_4648_vectors() {
+        assert_eq!(decode_base32("").unwrap(), b"");
+        assert_eq!(decode_base32("MY").unwrap(), b"f");
+        assert_eq!(decode_base32("MZXW6").unwrap(), b"foo");
+        assert_eq!(decode_base32("MZXW6YTB").unwrap(), b"fooba");
+        assert_eq!(decode_base32("mzxw6ytboi").unwrap(), b"foobar");
+        assert_eq!(decode_base32("MZXW6===="), None);
+    }
+
+    #[test]
+    fn peers_parse_with_and_without_brackets() {
+        let peer = parse_peer("10.0.0.1:6881").unwrap();
+        assert_eq!(peer.ip, "10.0.0.1");
+        assert_eq!(peer.port, 6881);
+
+        let peer = parse_peer("[2001:db8::1]:51413").unwrap();
+        assert_eq!(peer.ip, "2001:db8::1");
+        assert_eq!(peer.port, 51413);
+        assert_eq!(peer.to_string(), "[2001:db8::1]:51413");
+
+        assert!(parse_peer("10.0.0.1").is_err());
+        assert!(parse_peer("10.0.0.1:").is_err());
+        assert!(parse_peer("10.0.0.1:65536").is_err());
+    }
+
+    #[test]
+    fn select_only_accepts_indexes_and_ranges() {
+        assert_eq!(parse_select_only("0,2-3").unwrap(), vec![0..=0, 2..=3]);
+        assert_eq!(parse_select_only("5").unwrap(), vec![5..=5]);
+
+        for bad in ["", "1,", "3-2", "a", "1-", "-1", "1-2-3"] {
+            assert!(parse_select_only(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn parse_reads_every_key() {
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}&dn=a+file&tr.1=http%3A%2F%2Fa%2Fannounce\
+             &tr.2=udp%3A%2F%2Fb%3A80&ws=http%3A%2F%2Fseed%2F\
+             &x.pe=10.0.0.1%3A6881&x.pe=%5B2001%3Adb8%3A%3A1%5D%3A51413&so=0,2-3",
+            HASH
+        );
+        let magnet = MagnetLink::parse(&uri).unwrap();
+
+        assert_eq!(magnet.info_hash_str(), HASH);
+        assert_eq!(magnet.display_name.as_deref(), Some("a file"));
+        assert_eq!(magnet.trackers, ["http://a/announce", "udp://b:80"]);
+        assert_eq!(magnet.web_seeds, ["http://seed/"]);
+        assert_eq!(magnet.peers.len(), 2);
+        assert_eq!(magnet.peers[1].ip, "2001:db8::1");
+        assert_eq!(magnet.select_only, vec![0..=0, 2..=3]);
+    }
+
+    #[test]
+    fn bad_links_are_rejected() {
+        let links = [
+            format!("http://example.com/?xt=urn:btih:{}", HASH),
+            "magnet:?dn=name".to_string(),
+            "magnet:?xt=urn:btih:abc".to_string(),
+            format!("magnet:?xt=urn:btih:{}&x.pe=nowhere", HASH),
+            format!("magnet:?xt=urn:btih:{}&so=2-1", HASH),
+        ];
+
+        for link in links {
+            assert!(MagnetLink::parse(&link).is_err(), "{}", link);
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}&dn=a+file&tr=http%3A%2F%2Fa%2Fannounce\
+             &ws=http%3A%2F%2Fseed%2F&x.pe=%5B2001%3Adb8%3A%3A1%5D%3A51413&so=0%2C2-3",
+            HASH
+        );
+        let magnet = MagnetLink::parse(&uri).unwrap();
+        assert_eq!(magnet.to_string(), uri);
+
+        let reparsed = MagnetLink::parse(&magnet.to_string()).unwrap();
+        assert_eq!(reparsed.info_hash, magnet.info_hash);
+        assert_eq!(reparsed.trackers, magnet.trackers);
+        assert_eq!(reparsed.peers, magnet.peers);
+        assert_eq!(reparsed.select_only, magnet.select_only);
+    }
+}
@@ -0,0 +1,384 @@
+use std::io::{Error, ErrorKind, Read, Write};
+
+/// Largest frame accepted by default. A 16 KiB block plus its header fits
+/// easily, as do bitfields of torrents with millions of pieces.
+pub const MAX_MESSAGE_LENGTH: u32 = 1024 * 1024;
+
+/// A peer wire protocol message (BEP 3, plus the fast extension of BEP 6
+/// and the extension protocol of BEP 10).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    BitField(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Port(u16),
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    AllowedFast(u32),
+    /// The id is the extended message id; 0 is the extended handshake.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    /// A message id we don't know, kept so it can be skipped.
+    Unknown(u8, Vec<u8>),
+}
+
+impl Message {
+    /// Reads one length-prefixed message, refusing frames longer than
+    /// `max_length` before allocating for them.
+    pub fn read_from(reader: &mut impl Read, max_length: u32) -> Result<Message, Error> {
+        let mut length_buffer = [0; 4];
+        reader.read_exact(&mut length_buffer)?;
+
+        let length = u32::from_be_bytes(length_buffer);
+        if length == 0 {
+            return Ok(Message::KeepAlive);
+        }
+        if length > max_length {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Message of {} bytes exceeds {} bytes", length, max_length),
+            ));
+        }
+
+        let mut frame = vec![0; length as usize];
+        reader.read_exact(&mut frame)?;
+
+        Message::from_frame(&frame)
+    }
+
+    /// Decodes a message from its id and payload, i.e. a frame without the
+    /// length prefix.
+    pub fn from_frame(frame: &[u8]) -> Result<Message, Error> {
+        let Some((&id, payload)) = frame.split_first() else {
+            return Ok(Message::KeepAlive);
+        };
+
+        let message = match id {
+            0 => Message::Choke,
+            1 => Message::Unchoke,
+            2 => Message::Interested,
+            3 => Message::NotInterested,
+            4 => Message::Have(single_index(id, payload)?),
+            5 => Message::BitField(payload.to_vec()),
+            6 | 8 | 16 => {
+                let (index, begin, length) = block_fields(id, payload)?;
+                match id {
+                    6 => Message::Request {
+                        index,
+                        begin,
+                        length,
+                    },
+                    8 => Message::Cancel {
+                        index,
+                        begin,
+                        length,
+                    },
+                    _ => Message::RejectRequest {
+                        index,
+                        begin,
+                        length,
+                    },
+                }
+            }
+            7 => {
+                if payload.len() < 8 {
+                    return Err(malformed(id, payload.len()));
+                }
+                Message::Piece {
+                    index: be_u32(&payload[0..4]),
+                    begin: be_u32(&payload[4..8]),
+                    block: payload[8..].to_vec(),
+                }
+            }
+            9 => match payload {
+                [high, low] => Message::Port(u16::from_be_bytes([*high, *low])),
+                _ => return Err(malformed(id, payload.len())),
+            },
+            13 => Message::SuggestPiece(single_index(id, payload)?),
+            14 => Message::HaveAll,
+            15 => Message::HaveNone,
+            17 => Message::AllowedFast(single_index(id, payload)?),
+            20 => match payload.split_first() {
+                Some((&extended_id, payload)) => Message::Extended {
+                    id: extended_id,
+                    payload: payload.to_vec(),
+                },
+                None => return Err(malformed(id, 0)),
+            },
+            _ => Message::Unknown(id, payload.to_vec()),
+        };
+
+        Ok(message)
+    }
+
+    pub fn id(&self) -> Option<u8> {
+        let id = match self {
+            Message::KeepAlive => return None,
+            Message::Choke => 0,
+            Message::Unchoke => 1,
+            Message::Interested => 2,
+            Message::NotInterested => 3,
+            Message::Have(_) => 4,
+            Message::BitField(_) => 5,
+            Message::Request { .. } => 6,
+            Message::Piece { .. } => 7,
+            Message::Cancel { .. } => 8,
+            Message::Port(_) => 9,
+            Message::SuggestPiece(_) => 13,
+            Message::HaveAll => 14,
+            Message::HaveNone => 15,
+            Message::RejectRequest { .. } => 16,
+            Message::AllowedFast(_) => 17,
+            Message::Extended { .. } => 20,
+            Message::Unknown(id, _) => *id,
+        };
+
+        Some(id)
+    }
+
+    /// The message with its length prefix, ready to be written.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let Some(id) = self.id() else {
+            return vec![0; 4];
+        };
+
+        let mut payload = Vec::new();
+        match self {
+            Message::Have(index) | Message::SuggestPiece(index) | Message::AllowedFast(index) => {
+                payload.extend(index.to_be_bytes())
+            }
+            Message::BitField(bitfield) => payload.extend(bitfield),
+            Message::Request {
+                index,
+                begin,
+                length,
+            }
+            | Message::Cancel {
+                index,
+                begin,
+                length,
+            }
+            | Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                payload.extend(index.to_be_bytes());
+                payload.extend(begin.to_be_bytes());
+                payload.extend(length.to_be_bytes());
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                payload.extend(index.to_be_bytes());
+                payload.extend(begin.to_be_bytes());
+                payload.extend(block);
+            }
+            Message::Port(port) => payload.extend(port.to_be_bytes()),
+            Message::Extended {
+                id: extended_id,
+                payload: extended,
+            } => {
+                payload.push(*extended_id);
+                payload.extend(extended);
+            }
+            Message::Unknown(_, unknown) => payload.extend(unknown),
+            _ => {}
+        }
+
+        let mut message = Vec::with_capacity(payload.len() + 5);
+        message.extend((payload.len() as u32 + 1).to_be_bytes());
+        message.push(id);
+        message.extend(payload);
+
+        message
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> Result<(), Error> {
+        writer.write_all(&self.to_bytes())
+    }
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn single_index(id: u8, payload: &[u8]) -> Result<u32, Error> {
+    match payload.len() {
+        4 => Ok(be_u32(payload)),
+        length => Err(malformed(id, length)),
+    }
+}
+
+fn block_fields(id: u8, payload: &[u8]) -> Result<(u32, u32, u32), Error> {
+    match payload.len() {
+        12 => Ok((
+            be_u32(&payload[0..4]),
+            be_u32(&payload[4..8]),
+            be_u32(&payload[8..12]),
+        )),
+        length => Err(malformed(id, length)),
+    }
+}
+
+fn malformed(id: u8, length: usize) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Malformed message {} with a {} byte payload", id, length),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(bytes: &[u8]) -> Result<Message, Error> {
+        Message::read_from(&mut &bytes[..], MAX_MESSAGE_LENGTH)
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        let messages = [
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have(7),
+            Message::BitField(vec![0xff, 0x80]),
+            Message::BitField(Vec::new()),
+            Message::Request {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            Message::Piece {
+                index: 2,
+                begin: 32768,
+                block: vec![1, 2, 3],
+            },
+            Message::Piece {
+                index: 2,
+                begin: 0,
+                block: Vec::new(),
+            },
+            Message::Cancel {
+                index: 3,
+                begin: 0,
+                length: 16384,
+            },
+            Message::Port(6881),
+            Message::SuggestPiece(4),
+            Message::HaveAll,
+            Message::HaveNone,
+            Message::RejectRequest {
+                index: 5,
+                begin: 16384,
+                length: 8192,
+            },
+            Message::AllowedFast(u32::MAX),
+            Message::Extended {
+                id: 0,
+                payload: b"d1:md11:ut_metadatai1eee".to_vec(),
+            },
+            Message::Extended {
+                id: 3,
+                payload: Vec::new(),
+            },
+            Message::Unknown(99, vec![9, 8, 7]),
+        ];
+
+        for message in messages {
+            let bytes = message.to_bytes();
+            assert_eq!(read(&bytes).unwrap(), message, "{bytes:?}");
+        }
+    }
+
+    #[test]
+    fn wire_format_is_length_prefixed() {
+        assert_eq!(Message::KeepAlive.to_bytes(), [0, 0, 0, 0]);
+        assert_eq!(Message::Unchoke.to_bytes(), [0, 0, 0, 1, 1]);
+        assert_eq!(Message::Have(258).to_bytes(), [0, 0, 0, 5, 4, 0, 0, 1, 2]);
+        assert_eq!(Message::Port(6881).to_bytes(), [0, 0, 0, 3, 9, 0x1a, 0xe1]);
+    }
+
+    #[test]
+    fn unknown_ids_keep_their_payload() {
+        assert_eq!(
+            read(&[0, 0, 0, 3, 42, 1, 2]).unwrap(),
+            Message::Unknown(42, vec![1, 2])
+        );
+    }
+
+    #[test]
+    fn frames_over_the_limit_are_refused() {
+        let length = (MAX_MESSAGE_LENGTH + 1).to_be_bytes();
+        let err = read(&length).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        // at the limit the frame is read, and here runs out of input
+        let length = MAX_MESSAGE_LENGTH.to_be_bytes();
+        assert_eq!(read(&length).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn malformed_payload_lengths_are_errors() {
+        let frames: [&[u8]; 12] = [
+            &[4],
+            &[4, 0, 0, 0],
+            &[4, 0, 0, 0, 0, 0],
+            &[6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            &[8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            &[16, 0, 0, 0, 0],
+            &[7, 0, 0, 0, 0, 0, 0, 0],
+            &[9, 0],
+            &[9, 0, 0, 0],
+            &[13, 0, 0],
+            &[17],
+            &[20],
+        ];
+
+        for frame in frames {
+            let err = Message::from_frame(frame).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{frame:?}");
+        }
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        assert_eq!(
+            read(&[0, 0, 0, 5, 4, 0]).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+        assert_eq!(read(&[0, 0]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+}
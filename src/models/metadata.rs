@@ -0,0 +1,282 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use super::extension::{split_payload, ExtendedHandshake, ExtensionHandler};
+use super::handshake::HandShake;
+use super::info::{Info, MetaInfo};
+use super::magnet::MagnetLink;
+use super::peers::{PeerPool, SharedPeerPool};
+use super::pex::PexHandler;
+use super::tracker::TrackerRequest;
+use crate::{bencode_de, bencode_ser};
+
+const UT_METADATA: &str = "ut_metadata";
+const METADATA_PIECE_SIZE: usize = 16 * 1024;
+// BEP 9 sets no limit; real info dictionaries are far below this
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MSG_REQUEST: i64 = 0;
+const MSG_DATA: i64 = 1;
+const MSG_REJECT: i64 = 2;
+
+#[derive(Serialize, Deserialize)]
+struct MetadataMessage {
+    msg_type: i64,
+    piece: i64,
+    total_size: Option<i64>,
+}
+
+/// Gets the `info` dictionary of a magnet link from its peers (BEP 9) and
+/// returns it as a `MetaInfo` announcing to the link's first HTTP tracker,
+/// along with the peers gathered on the way for the download to use.
+pub fn download_metadata(
+    magnet: &MagnetLink,
+    peer_id: &str,
+) -> Result<(MetaInfo, SharedPeerPool), Error> {
+    let tracker = magnet
+        .trackers
+        .iter()
+        .find(|tracker| TrackerRequest::is_supported(tracker))
+        .cloned()
+        .unwrap_or_default();
+    let mut last_error = Error::new(ErrorKind::NotFound, "Magnet link has no peers");
+
+    let mut peers = Vec::new();
+    if !tracker.is_empty() {
+        // the length isn't known before the metadata, but trackers want one
+        let tracker_request = TrackerRequest::new(
+            &tracker,
+            &magnet.info_hash,
+            peer_id.to_string(),
+            6881,
+            0,
+            0,
+            "999",
+        );
+        // the link's own peers may still have the metadata
+        match tracker_request.get_peers() {
+            Ok(tracker_peers) => peers = tracker_peers,
+            Err(err) => last_error = err,
+        }
+    }
+
+    // peers learned over PEX while we try others join the queue
+    let pool = PeerPool::shared(magnet.peers.iter().cloned().chain(peers));
+
+    loop {
+        let Some(peer) = pool.lock().expect("Peer pool poisoned").next_candidate() else {
+            break;
+        };
+
+        let mut handshake = HandShake::new(&magnet.info_hash, &peer.ip, peer.port, peer_id);
+        handshake.enable_extensions();
+        handshake
+            .extensions
+            .register(Box::new(MetadataHandler::new(&magnet.info_hash)));
+        handshake
+            .extensions
+            .register(Box::new(PexHandler::new(pool.clone(), peer.clone())));
+
+        let mut reachable = false;
+        let metadata = handshake.try_handshake().and_then(|_| {
+            reachable = true;
+            pool.lock()
+                .expect("Peer pool poisoned")
+                .mark_connected(&peer);
+            fetch_metadata(&mut handshake)
+        });
+
+        let mut pool_guard = pool.lock().expect("Peer pool poisoned");
+        pool_guard.mark_disconnected(&peer);
+        // it may still serve pieces once the metadata is known
+        if reachable {
+            pool_guard.requeue(peer.clone());
+        }
+        drop(pool_guard);
+
+        match metadata {
+            Ok(metadata) => {
+                let meta_info = meta_info_from_metadata(magnet, &tracker, &metadata)?;
+                return Ok((meta_info, pool));
+            }
+            Err(err) => last_error = Error::new(err.kind(), format!("{}: {}", peer, err)),
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Waits on an already connected peer until its metadata has been
+/// downloaded by the registered `MetadataHandler`.
+pub fn fetch_metadata(handshake: &mut HandShake) -> Result<Vec<u8>, Error> {
+    if !handshake.supports_extensions() {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "Peer does not support the extension protocol",
+        ));
+    }
+
+    if let Some(stream) = &handshake.socket {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    }
+
+    loop {
+        handshake.read_message()?;
+
+        let extensions = &mut handshake.extensions;
+        if let Some(err) = extensions.failure(UT_METADATA) {
+            return Err(Error::new(err.kind(), err.to_string()));
+        }
+        if extensions.peer_handshake().is_some() && extensions.peer_id(UT_METADATA).is_none() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "Peer does not support ut_metadata",
+            ));
+        }
+
+        let handler = extensions
+            .handler_mut::<MetadataHandler>()
+            .expect("No ut_metadata handler registered");
+        if let Some(metadata) = handler.take_metadata() {
+            return Ok(metadata);
+        }
+    }
+}
+
+/// Downloads the `info` dictionary one piece at a time (BEP 9).
+pub struct MetadataHandler {
+    info_hash: Vec<u8>,
+    size: usize,
+    metadata: Vec<u8>,
+    complete: bool,
+}
+
+impl MetadataHandler {
+    pub fn new(info_hash: &[u8]) -> MetadataHandler {
+        MetadataHandler {
+            info_hash: info_hash.to_vec(),
+            size: 0,
+            metadata: Vec::new(),
+            complete: false,
+        }
+    }
+
+    /// The `info` dictionary, once every piece arrived and it matched the
+    /// info hash.
+    pub fn take_metadata(&mut self) -> Option<Vec<u8>> {
+        if !self.complete {
+            return None;
+        }
+
+        self.complete = false;
+        Some(std::mem::take(&mut self.metadata))
+    }
+
+    fn request(&self, piece: usize) -> Vec<u8> {
+        let request = MetadataMessage {
+            msg_type: MSG_REQUEST,
+            piece: piece as i64,
+            total_size: None,
+        };
+
+        bencode_ser::to_bytes(&request).expect("Failed to encode metadata request")
+    }
+}
+
+impl ExtensionHandler for MetadataHandler {
+    fn name(&self) -> &'static str {
+        UT_METADATA
+    }
+
+    fn on_handshake(&mut self, handshake: &ExtendedHandshake) -> Result<Vec<Vec<u8>>, Error> {
+        self.size = match handshake.metadata_size {
+            Some(size) if size > 0 && size as usize <= MAX_METADATA_SIZE => size as usize,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Peer sent no usable metadata_size",
+                ))
+            }
+        };
+        self.metadata = Vec::with_capacity(self.size);
+
+        Ok(vec![self.request(0)])
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        let (dict, data) = split_payload(payload)?;
+        let message = bencode_de::from_bytes::<MetadataMessage>(dict)?;
+        let piece = self.metadata.len() / METADATA_PIECE_SIZE;
+
+        match message.msg_type {
+            MSG_DATA => {}
+            MSG_REJECT => {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("Peer rejected metadata piece {}", piece),
+                ))
+            }
+            // requests for our metadata go unanswered; we only download
+            _ => return Ok(Vec::new()),
+        }
+
+        let expected_length = METADATA_PIECE_SIZE.min(self.size - self.metadata.len());
+        if self.complete || message.piece != piece as i64 || data.len() != expected_length {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unexpected reply to metadata piece {}", piece),
+            ));
+        }
+
+        self.metadata.extend_from_slice(data);
+
+        if self.metadata.len() < self.size {
+            return Ok(vec![self.request(piece + 1)]);
+        }
+
+        if Sha1::digest(&self.metadata)[..] != self.info_hash[..] {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Metadata does not match the info hash",
+            ));
+        }
+
+        self.complete = true;
+        Ok(Vec::new())
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+fn meta_info_from_metadata(
+    magnet: &MagnetLink,
+    tracker: &str,
+    metadata: &[u8],
+) -> Result<MetaInfo, Error> {
+    let info = bencode_de::from_bytes::<Info>(metadata)?;
+    info.validate()?;
+
+    Ok(MetaInfo {
+        announce: tracker.to_string(),
+        announce_list: (magnet.trackers.len() > 1)
+            .then(|| magnet.trackers.iter().map(|tr| vec![tr.clone()]).collect()),
+        comment: None,
+        created_by: None,
+        creation_date: None,
+        encoding: None,
+        url_list: (!magnet.web_seeds.is_empty()).then(|| magnet.web_seeds.clone()),
+        httpseeds: None,
+        nodes: None,
+        info,
+        extra: BTreeMap::new(),
+        raw_info_hash: Some(magnet.info_hash),
+    })
+}
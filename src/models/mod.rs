@@ -1,4 +1,17 @@
-pub mod info;
-pub mod tracker;
+pub mod create;
+pub mod download;
+pub mod engine;
+pub mod extension;
 pub mod handshake;
+pub mod hasher;
+pub mod info;
+pub mod magnet;
+pub mod message;
+pub mod metadata;
 pub mod peers;
+pub mod pex;
+pub mod picker;
+pub mod pipeline;
+pub mod storage;
+pub mod tracker;
+pub mod verify;
@@ -1,96 +1,206 @@
-use std::{net::TcpStream, io::Read};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex},
+};
 
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Peer {
     pub ip: String,
     pub port: u16,
 }
 
-pub struct PeerMessage {
-    pub length: u32,
-    pub message_type: PeerMessageType,
-    pub payload: Vec<u8>,
+impl Peer {
+    /// Parses a compact peer: 4 or 16 address bytes then a big-endian port.
+    pub fn from_compact(bytes: &[u8]) -> Option<Peer> {
+        let (ip, port) = match bytes.len() {
+            6 => {
+                let octets: [u8; 4] = bytes[..4].try_into().ok()?;
+                (IpAddr::from(Ipv4Addr::from(octets)), &bytes[4..])
+            }
+            18 => {
+                let octets: [u8; 16] = bytes[..16].try_into().ok()?;
+                (IpAddr::from(Ipv6Addr::from(octets)), &bytes[16..])
+            }
+            _ => return None,
+        };
+
+        Some(Peer {
+            ip: ip.to_string(),
+            port: u16::from_be_bytes([port[0], port[1]]),
+        })
+    }
+
+    /// The compact form, or None when `ip` isn't a literal address.
+    pub fn to_compact(&self) -> Option<Vec<u8>> {
+        let mut compact = match self.ip.parse::<IpAddr>().ok()? {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        compact.extend(self.port.to_be_bytes());
+
+        Some(compact)
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.ip.contains(':')
+    }
 }
 
-impl PeerMessage {
-    pub fn from_socket(stream: &mut TcpStream) -> Result<PeerMessage, String> {
-        let mut length_buffer = [0; 4];
-        stream
-            .read_exact(&mut length_buffer)
-            .expect("Failed to read from stream");
+impl fmt::Display for Peer {
+    // IPv6 addresses are bracketed so the port stays unambiguous
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ipv6() {
+            write!(f, "[{}]:{}", self.ip, self.port)
+        } else {
+            write!(f, "{}:{}", self.ip, self.port)
+        }
+    }
+}
 
-        let length = u32::from_be_bytes(length_buffer);
+/// Peers known to one download, gathered from the tracker, the magnet link
+/// and peer exchange, and shared between its connections.
+#[derive(Default)]
+pub struct PeerPool {
+    known: HashSet<Peer>,
+    candidates: VecDeque<Peer>,
+    connected: HashSet<Peer>,
+    flags: HashMap<Peer, u8>,
+    hash_failures: HashMap<Peer, u32>,
+    banned: HashSet<Peer>,
+    /// Peers only PEX told us about, which PEX may take back.
+    from_pex: HashSet<Peer>,
+}
 
-        let mut message_buffer = vec![0; length as usize];
-        stream
-            .read_exact(&mut message_buffer)
-            .expect("Failed to read from stream");
+pub type SharedPeerPool = Arc<Mutex<PeerPool>>;
 
-        let message_type = PeerMessageType::from(&message_buffer[0]);
+/// Peers a pool keeps track of at most; later ones are ignored.
+pub const MAX_KNOWN_PEERS: usize = 2000;
 
-        let payload = message_buffer[1..].to_vec();
+/// Pieces a peer may fail to hash correctly before it is banned.
+pub const MAX_HASH_FAILURES: u32 = 2;
 
-        Ok(PeerMessage {
-            length,
-            message_type,
-            payload,
-        })
+impl PeerPool {
+    pub fn new() -> PeerPool {
+        PeerPool::default()
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut message = vec![];
-        message.extend(self.length.to_be_bytes().to_vec());
-        message.push(u8::from(self.message_type.clone()));
-        message.extend(self.payload.clone());
+    pub fn shared(peers: impl IntoIterator<Item = Peer>) -> SharedPeerPool {
+        let mut pool = PeerPool::new();
+        for peer in peers {
+            pool.add(peer);
+        }
 
-        message
+        Arc::new(Mutex::new(pool))
     }
-}
 
-#[derive(Eq, PartialEq, Clone, Debug)]
-pub enum PeerMessageType {
-    Choke,
-    Unchoke,
-    Interested,
-    NotInterested,
-    Have,
-    BitField,
-    Request,
-    Piece,
-    Cancel,
-    Port,
-}
+    /// Adds a peer not seen before to the back of the connect queue.
+    pub fn add(&mut self, peer: Peer) -> bool {
+        self.insert(peer, false)
+    }
 
-impl From<&u8> for PeerMessageType {
-    fn from(byte: &u8) -> Self {
-        match byte {
-            0 => PeerMessageType::Choke,
-            1 => PeerMessageType::Unchoke,
-            2 => PeerMessageType::Interested,
-            3 => PeerMessageType::NotInterested,
-            4 => PeerMessageType::Have,
-            5 => PeerMessageType::BitField,
-            6 => PeerMessageType::Request,
-            7 => PeerMessageType::Piece,
-            8 => PeerMessageType::Cancel,
-            9 => PeerMessageType::Port,
-            _ => panic!("Unknown message type"),
+    /// Like `add`, for a peer another peer told us about.
+    pub fn add_from_pex(&mut self, peer: Peer) -> bool {
+        self.insert(peer, true)
+    }
+
+    fn insert(&mut self, peer: Peer, from_pex: bool) -> bool {
+        if self.known.contains(&peer) {
+            if !from_pex {
+                self.from_pex.remove(&peer);
+            }
+            return false;
+        }
+        if self.known.len() >= MAX_KNOWN_PEERS {
+            return false;
         }
+
+        self.known.insert(peer.clone());
+        if from_pex {
+            self.from_pex.insert(peer.clone());
+        }
+        self.candidates.push_back(peer);
+        true
     }
-}
 
-impl From<PeerMessageType> for u8 {
-    fn from(message: PeerMessageType) -> Self {
-        match message {
-            PeerMessageType::Choke => 0,
-            PeerMessageType::Unchoke => 1,
-            PeerMessageType::Interested => 2,
-            PeerMessageType::NotInterested => 3,
-            PeerMessageType::Have => 4,
-            PeerMessageType::BitField => 5,
-            PeerMessageType::Request => 6,
-            PeerMessageType::Piece => 7,
-            PeerMessageType::Cancel => 8,
-            PeerMessageType::Port => 9,
+    /// Records the PEX flags another peer reported for `peer`, if the pool
+    /// knows it.
+    pub fn set_flags(&mut self, peer: &Peer, flags: u8) {
+        if self.known.contains(peer) {
+            self.flags.insert(peer.clone(), flags);
+        }
+    }
+
+    pub fn flags(&self, peer: &Peer) -> u8 {
+        self.flags.get(peer).copied().unwrap_or(0)
+    }
+
+    /// Forgets a peer we haven't tried yet.
+    pub fn drop_candidate(&mut self, peer: &Peer) {
+        self.candidates.retain(|candidate| candidate != peer);
+    }
+
+    /// Forgets a peer PEX reported as gone, unless we also learned of it
+    /// elsewhere: PEX only reflects what the remote peer sees.
+    pub fn drop_pex_candidate(&mut self, peer: &Peer) {
+        if self.from_pex.contains(peer) {
+            self.drop_candidate(peer);
+        }
+    }
+
+    /// The next peer to connect to, oldest first.
+    pub fn next_candidate(&mut self) -> Option<Peer> {
+        self.candidates.pop_front()
+    }
+
+    pub fn has_candidates(&self) -> bool {
+        !self.candidates.is_empty()
+    }
+
+    /// Puts a peer that is still usable back at the end of the queue.
+    pub fn requeue(&mut self, peer: Peer) {
+        if !self.banned.contains(&peer) && !self.candidates.contains(&peer) {
+            self.candidates.push_back(peer);
+        }
+    }
+
+    /// Counts a piece from `peer` that failed its hash check, and bans the
+    /// peer for the rest of the session once it reaches the limit. Returns
+    /// whether the peer is now banned.
+    pub fn record_hash_failure(&mut self, peer: &Peer) -> bool {
+        let failures = self.hash_failures.entry(peer.clone()).or_insert(0);
+        *failures += 1;
+
+        if *failures >= MAX_HASH_FAILURES {
+            self.banned.insert(peer.clone());
+            self.drop_candidate(peer);
         }
+
+        self.banned.contains(peer)
+    }
+
+    pub fn is_banned(&self, peer: &Peer) -> bool {
+        self.banned.contains(peer)
+    }
+
+    pub fn mark_connected(&mut self, peer: &Peer) {
+        self.connected.insert(peer.clone());
+    }
+
+    pub fn mark_disconnected(&mut self, peer: &Peer) {
+        self.connected.remove(peer);
+    }
+
+    pub fn connected(&self) -> &HashSet<Peer> {
+        &self.connected
+    }
+
+    pub fn len(&self) -> usize {
+        self.known.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.known.is_empty()
     }
 }
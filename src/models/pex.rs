@@ -0,0 +1,240 @@
+use std::any::Any;
+use std::collections::BTreeSet;
+use std::io::Error;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use super::extension::{split_payload, ExtensionHandler};
+use super::peers::{Peer, SharedPeerPool};
+use crate::{bencode_de, bencode_ser};
+
+const UT_PEX: &str = "ut_pex";
+// BEP 11: no more than one message a minute, and at most 50 peers added
+// and 50 dropped in each
+const PEX_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_PEX_PEERS: usize = 50;
+
+/// `added.f` flag: the peer is a seed.
+pub const PEX_FLAG_SEED: u8 = 0x02;
+/// `added.f` flag: the peer accepts incoming connections.
+pub const PEX_FLAG_REACHABLE: u8 = 0x10;
+
+#[derive(Default, Serialize, Deserialize)]
+struct PexMessage {
+    added: Option<ByteBuf>,
+    #[serde(rename = "added.f")]
+    added_flags: Option<ByteBuf>,
+    added6: Option<ByteBuf>,
+    #[serde(rename = "added6.f")]
+    added6_flags: Option<ByteBuf>,
+    dropped: Option<ByteBuf>,
+    dropped6: Option<ByteBuf>,
+}
+
+/// Peer exchange (BEP 11). Peers learned from the remote peer go into the
+/// shared pool, and the pool's connected peers are advertised back to it.
+///
+/// Must not be registered for private torrents.
+pub struct PexHandler {
+    pool: SharedPeerPool,
+    remote: Peer,
+    advertised: BTreeSet<Peer>,
+    last_sent: Option<Instant>,
+}
+
+impl PexHandler {
+    pub fn new(pool: SharedPeerPool, remote: Peer) -> PexHandler {
+        PexHandler {
+            pool,
+            remote,
+            advertised: BTreeSet::new(),
+            last_sent: None,
+        }
+    }
+}
+
+impl ExtensionHandler for PexHandler {
+    fn name(&self) -> &'static str {
+        UT_PEX
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        let (dict, _) = split_payload(payload)?;
+        let message = bencode_de::from_bytes::<PexMessage>(dict)?;
+
+        let mut pool = self.pool.lock().expect("Peer pool poisoned");
+
+        // anything past the first MAX_PEX_PEERS of each kind is ignored
+        let mut budget = MAX_PEX_PEERS;
+        let added = [
+            (&message.added, &message.added_flags, 6),
+            (&message.added6, &message.added6_flags, 18),
+        ];
+        for (peers, flags, size) in added {
+            let flags: &[u8] = flags.as_deref().map_or(&[], |flags| flags);
+            let chunks = peers.iter().flat_map(|p| p.chunks_exact(size));
+            for (index, chunk) in chunks.take(budget).enumerate() {
+                budget -= 1;
+                let Some(peer) = Peer::from_compact(chunk) else {
+                    continue;
+                };
+                pool.add_from_pex(peer.clone());
+                if let Some(&flags) = flags.get(index) {
+                    pool.set_flags(&peer, flags);
+                }
+            }
+        }
+
+        let mut budget = MAX_PEX_PEERS;
+        let dropped = [(&message.dropped, 6), (&message.dropped6, 18)];
+        for (peers, size) in dropped {
+            let chunks = peers.iter().flat_map(|p| p.chunks_exact(size));
+            for chunk in chunks.take(budget) {
+                budget -= 1;
+                if let Some(peer) = Peer::from_compact(chunk) {
+                    pool.drop_pex_candidate(&peer);
+                }
+            }
+        }
+
+        Ok(Vec::new())
+    }
+
+    // sends what changed in our connected peers since the last message
+    fn poll(&mut self) -> Vec<Vec<u8>> {
+        if self
+            .last_sent
+            .is_some_and(|sent| sent.elapsed() < PEX_INTERVAL)
+        {
+            return Vec::new();
+        }
+
+        let connected: BTreeSet<Peer> = {
+            let pool = self.pool.lock().expect("Peer pool poisoned");
+            pool.connected()
+                .iter()
+                .filter(|peer| **peer != self.remote)
+                .cloned()
+                .collect()
+        };
+
+        let added: Vec<Peer> = connected
+            .difference(&self.advertised)
+            .take(MAX_PEX_PEERS)
+            .cloned()
+            .collect();
+        let dropped: Vec<Peer> = self
+            .advertised
+            .difference(&connected)
+            .take(MAX_PEX_PEERS)
+            .cloned()
+            .collect();
+
+        if added.is_empty() && dropped.is_empty() {
+            return Vec::new();
+        }
+
+        let mut message = PexMessage::default();
+        for peer in &added {
+            let Some(compact) = peer.to_compact() else {
+                continue;
+            };
+            let (peers, flags) = if peer.is_ipv6() {
+                (&mut message.added6, &mut message.added6_flags)
+            } else {
+                (&mut message.added, &mut message.added_flags)
+            };
+            peers.get_or_insert_with(ByteBuf::new).extend(compact);
+            // we only advertise peers we reached, so they accept connections
+            flags
+                .get_or_insert_with(ByteBuf::new)
+                .push(PEX_FLAG_REACHABLE);
+        }
+        for peer in &dropped {
+            let Some(compact) = peer.to_compact() else {
+                continue;
+            };
+            let peers = if peer.is_ipv6() {
+                &mut message.dropped6
+            } else {
+                &mut message.dropped
+            };
+            peers.get_or_insert_with(ByteBuf::new).extend(compact);
+        }
+
+        self.advertised.extend(added);
+        for peer in &dropped {
+            self.advertised.remove(peer);
+        }
+        self.last_sent = Some(Instant::now());
+
+        vec![bencode_ser::to_bytes(&message).expect("Failed to encode ut_pex message")]
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::peers::PeerPool;
+
+    fn peer(index: u16) -> Peer {
+        Peer {
+            ip: format!("10.0.{}.{}", index / 256, index % 256),
+            port: 6881,
+        }
+    }
+
+    fn compact(peers: impl Iterator<Item = Peer>) -> Option<ByteBuf> {
+        Some(ByteBuf::from(
+            peers
+                .flat_map(|peer| peer.to_compact().unwrap())
+                .collect::<Vec<u8>>(),
+        ))
+    }
+
+    fn receive(handler: &mut PexHandler, message: &PexMessage) {
+        let payload = bencode_ser::to_bytes(message).unwrap();
+        handler.on_message(&payload).unwrap();
+    }
+
+    #[test]
+    fn added_peers_are_capped_per_message() {
+        let pool = PeerPool::shared([]);
+        let mut handler = PexHandler::new(pool.clone(), peer(0));
+
+        let message = PexMessage {
+            added: compact((1..=200).map(peer)),
+            ..PexMessage::default()
+        };
+        receive(&mut handler, &message);
+
+        assert_eq!(pool.lock().unwrap().len(), MAX_PEX_PEERS);
+    }
+
+    #[test]
+    fn dropped_only_forgets_peers_pex_reported() {
+        let pool = PeerPool::shared([peer(1)]);
+        let mut handler = PexHandler::new(pool.clone(), peer(0));
+
+        let message = PexMessage {
+            added: compact([peer(1), peer(2)].into_iter()),
+            ..PexMessage::default()
+        };
+        receive(&mut handler, &message);
+        let message = PexMessage {
+            dropped: compact([peer(1), peer(2)].into_iter()),
+            ..PexMessage::default()
+        };
+        receive(&mut handler, &message);
+
+        let mut pool = pool.lock().unwrap();
+        assert_eq!(pool.next_candidate(), Some(peer(1)));
+        assert_eq!(pool.next_candidate(), None);
+    }
+}
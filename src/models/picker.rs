@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Pieces picked at random before switching to rarest-first.
+pub const RANDOM_FIRST_PIECES: usize = 4;
+
+/// Chooses which piece to download next.
+///
+/// Availability counts how many connected peers have each piece, from
+/// their bitfields and `Have` messages. Pieces are picked rarest first, so
+/// pieces only a few peers have are fetched while those peers are still
+/// around, and ties are broken at random so that downloaders spread out
+/// over the swarm. Until the first few pieces are complete, pieces are
+/// picked at random instead, since a rare piece is slow to get from the
+/// few peers that have it.
+pub struct PiecePicker {
+    availability: Vec<u32>,
+    // the pieces counted for each peer, by connection
+    peers: HashMap<usize, Vec<bool>>,
+    rng: XorShift,
+}
+
+impl PiecePicker {
+    pub fn new(piece_count: usize) -> PiecePicker {
+        PiecePicker {
+            availability: vec![0; piece_count],
+            peers: HashMap::new(),
+            rng: XorShift::from_time(),
+        }
+    }
+
+    /// Number of connected peers that have the piece.
+    pub fn availability(&self, index: usize) -> u32 {
+        self.availability.get(index).copied().unwrap_or(0)
+    }
+
+    /// Counts the pieces of a peer's bitfield (or have-all, have-none),
+    /// replacing whatever was counted for it before.
+    pub fn set_peer(&mut self, peer: usize, has: impl Fn(usize) -> bool) {
+        self.remove_peer(peer);
+
+        let pieces: Vec<bool> = (0..self.availability.len()).map(has).collect();
+        for (count, _) in self
+            .availability
+            .iter_mut()
+            .zip(&pieces)
+            .filter(|(_, has)| **has)
+        {
+            *count += 1;
+        }
+        self.peers.insert(peer, pieces);
+    }
+
+    /// Counts a piece from a peer's `Have` message.
+    pub fn add_have(&mut self, peer: usize, index: usize) {
+        let piece_count = self.availability.len();
+        let pieces = self
+            .peers
+            .entry(peer)
+            .or_insert_with(|| vec![false; piece_count]);
+
+        if let Some(has) = pieces.get_mut(index) {
+            if !*has {
+                *has = true;
+                self.availability[index] += 1;
+            }
+        }
+    }
+
+    /// Stops counting the pieces of a peer that disconnected.
+    pub fn remove_peer(&mut self, peer: usize) {
+        let Some(pieces) = self.peers.remove(&peer) else {
+            return;
+        };
+
+        for (count, _) in self
+            .availability
+            .iter_mut()
+            .zip(&pieces)
+            .filter(|(_, has)| **has)
+        {
+            *count -= 1;
+        }
+    }
+
+    /// Picks a piece to start among the ones `candidate` accepts, given how
+    /// many pieces are complete so far.
+    pub fn pick(&mut self, completed: usize, candidate: impl Fn(usize) -> bool) -> Option<usize> {
+        let pieces = (0..self.availability.len()).filter(|&index| candidate(index));
+
+        if completed < RANDOM_FIRST_PIECES {
+            pick_lowest(&mut self.rng, pieces, |_| 0)
+        } else {
+            self.rarest(pieces)
+        }
+    }
+
+    /// The rarest of `pieces`, ties broken at random.
+    pub fn rarest(&mut self, pieces: impl Iterator<Item = usize>) -> Option<usize> {
+        let availability = &self.availability;
+        let rng = &mut self.rng;
+
+        pick_lowest(rng, pieces, |index| availability[index])
+    }
+}
+
+// the piece with the lowest key; among equal keys each is equally likely,
+// by reservoir sampling
+fn pick_lowest(
+    rng: &mut XorShift,
+    pieces: impl Iterator<Item = usize>,
+    key: impl Fn(usize) -> u32,
+) -> Option<usize> {
+    let mut picked = None;
+    let mut lowest = u32::MAX;
+    let mut ties = 0;
+
+    for index in pieces {
+        let key = key(index);
+        if picked.is_none() || key < lowest {
+            picked = Some(index);
+            lowest = key;
+            ties = 1;
+        } else if key == lowest {
+            ties += 1;
+            if rng.below(ties) == 0 {
+                picked = Some(index);
+            }
+        }
+    }
+
+    picked
+}
+
+// xorshift64*; picking pieces needs spread, not unpredictability
+struct XorShift(u64);
+
+impl XorShift {
+    fn from_time() -> XorShift {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+
+        // the state must never be zero
+        XorShift(nanos | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn availability_follows_peers() {
+        let mut picker = PiecePicker::new(4);
+
+        picker.set_peer(0, |index| index < 2);
+        picker.set_peer(1, |index| index == 1);
+        assert_eq!(picker.availability(0), 1);
+        assert_eq!(picker.availability(1), 2);
+
+        // a new bitfield replaces what was counted for the peer
+        picker.set_peer(0, |index| index == 3);
+        assert_eq!(picker.availability(0), 0);
+        assert_eq!(picker.availability(3), 1);
+
+        picker.remove_peer(1);
+        picker.remove_peer(1);
+        assert_eq!(picker.availability(1), 0);
+        assert_eq!(picker.availability(3), 1);
+    }
+
+    #[test]
+    fn haves_count_once_and_only_in_range() {
+        let mut picker = PiecePicker::new(4);
+
+        picker.add_have(0, 2);
+        picker.add_have(0, 2);
+        picker.add_have(0, 4);
+        picker.add_have(0, usize::MAX);
+        picker.add_have(1, 2);
+        assert_eq!(picker.availability(2), 2);
+        assert_eq!(picker.availability(4), 0);
+
+        picker.remove_peer(0);
+        assert_eq!(picker.availability(2), 1);
+    }
+
+    #[test]
+    fn rarest_piece_is_picked_once_enough_are_complete() {
+        let mut picker = PiecePicker::new(4);
+        picker.set_peer(0, |_| true);
+        picker.set_peer(1, |index| index != 2);
+
+        for _ in 0..100 {
+            assert_eq!(picker.pick(RANDOM_FIRST_PIECES, |_| true), Some(2));
+        }
+        // ties go to any of them
+        let mut picked = [false; 4];
+        for _ in 0..200 {
+            picked[picker
+                .pick(RANDOM_FIRST_PIECES, |index| index != 2)
+                .unwrap()] = true;
+        }
+        assert_eq!(picked, [true, true, false, true]);
+        assert_eq!(picker.pick(RANDOM_FIRST_PIECES, |_| false), None);
+    }
+
+    #[test]
+    fn first_pieces_are_picked_at_random() {
+        let mut picker = PiecePicker::new(4);
+        picker.set_peer(0, |_| true);
+        picker.set_peer(1, |index| index != 2);
+
+        let mut picked = [false; 4];
+        for _ in 0..200 {
+            let index = picker
+                .pick(RANDOM_FIRST_PIECES - 1, |index| index != 0)
+                .unwrap();
+            picked[index] = true;
+        }
+        assert_eq!(picked, [false, true, true, true]);
+    }
+}
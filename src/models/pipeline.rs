@@ -0,0 +1,271 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+
+use super::extension::EXTENDED_HANDSHAKE_ID;
+use super::handshake::HandShake;
+use super::message::Message;
+
+/// Default cap on outstanding block requests to one peer.
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 64;
+
+/// Size of the blocks pieces are requested in; the last block of a piece
+/// may be shorter.
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+// requests a peer may reject while it lets us ask before we give up on it
+const MAX_REJECTS: usize = 32;
+// requests kept outstanding before anything has been measured
+const INITIAL_WINDOW: usize = 4;
+const MIN_WINDOW: usize = 2;
+// blocks requested beyond the bandwidth-delay product, so the peer's send
+// queue doesn't run dry between our requests
+const HEADROOM: usize = 2;
+
+/// Number of block requests to keep outstanding on a connection, sized to
+/// the bandwidth-delay product measured on it.
+///
+/// The rate is sampled once per round trip. While the window is what
+/// limits the rate, each sample grows it by half; once the link is full
+/// the samples stop rising and the window settles a little above the
+/// bandwidth-delay product. It shrinks more slowly than it grows.
+pub struct RequestWindow {
+    size: usize,
+    max: usize,
+    sample_start: Option<Instant>,
+    sample_bytes: u64,
+    // the fastest round trip seen; slower ones include time the request
+    // spent queued behind earlier ones at the peer
+    min_rtt: Option<Duration>,
+}
+
+impl RequestWindow {
+    pub fn new(max: usize) -> RequestWindow {
+        let max = max.max(1);
+
+        RequestWindow {
+            size: INITIAL_WINDOW.min(max),
+            max,
+            sample_start: None,
+            sample_bytes: 0,
+            min_rtt: None,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Lowers the cap to what the peer said it will queue (`reqq`).
+    pub fn limit_to(&mut self, reqq: usize) {
+        self.max = self.max.min(reqq.max(1));
+        self.size = self.size.min(self.max);
+    }
+
+    // the first sample starts with the first request, so waiting to be
+    // unchoked doesn't count against the rate
+    pub fn on_request(&mut self) {
+        self.sample_start.get_or_insert_with(Instant::now);
+    }
+
+    /// Records a block that arrived `rtt` after it was requested.
+    pub fn on_block(&mut self, length: usize, rtt: Duration) {
+        self.sample_bytes += length as u64;
+        let min_rtt = self.min_rtt.map_or(rtt, |min_rtt| min_rtt.min(rtt));
+        self.min_rtt = Some(min_rtt);
+
+        let Some(sample_start) = self.sample_start else {
+            return;
+        };
+        let elapsed = sample_start.elapsed();
+        if elapsed < min_rtt || elapsed.is_zero() {
+            return;
+        }
+
+        let rate = self.sample_bytes as f64 / elapsed.as_secs_f64();
+        let bdp_blocks = (rate * min_rtt.as_secs_f64() / BLOCK_SIZE as f64).ceil() as usize;
+        self.sample_start = Some(Instant::now());
+        self.sample_bytes = 0;
+
+        let target =
+            (bdp_blocks + bdp_blocks / 2 + HEADROOM).clamp(MIN_WINDOW.min(self.max), self.max);
+        // a single slow sample is often just a burst arriving late, so
+        // shrink gradually
+        self.size = if target >= self.size {
+            target
+        } else {
+            self.size - ((self.size - target - 1) / 4 + 1)
+        };
+    }
+}
+
+/// The block requests outstanding on one connection, sent through a
+/// `RequestWindow`.
+pub struct PeerRequests {
+    window: RequestWindow,
+    // by (piece, offset), with their length and send time
+    in_flight: HashMap<(u32, u32), (u32, Instant)>,
+    // blocks the peer rejected while it let us ask for them, as (piece,
+    // offset); not asked for again until its choke or allowed-fast state
+    // changes
+    rejected: HashSet<(u32, u32)>,
+    rejects: usize,
+}
+
+/// What a message from the peer meant for our requests.
+pub enum RequestUpdate<'a> {
+    /// A block we asked for, with the length we asked for.
+    Block {
+        index: u32,
+        begin: u32,
+        data: &'a [u8],
+    },
+    /// Requests the peer won't answer, as (piece, offset, length), lowest
+    /// offset first; they can be asked for again.
+    Dropped(Vec<(u32, u32, u32)>),
+    None,
+}
+
+impl PeerRequests {
+    pub fn new(max_in_flight: usize) -> PeerRequests {
+        PeerRequests {
+            window: RequestWindow::new(max_in_flight),
+            in_flight: HashMap::new(),
+            rejected: HashSet::new(),
+            rejects: 0,
+        }
+    }
+
+    /// Whether the window leaves room for another request.
+    pub fn has_room(&self) -> bool {
+        self.in_flight.len() < self.window.size()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.in_flight.is_empty()
+    }
+
+    /// Outstanding requests as (piece, offset).
+    pub fn outstanding(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.in_flight.keys().copied()
+    }
+
+    /// Whether the peer rejected the block since its state last changed.
+    pub fn is_rejected(&self, index: u32, begin: u32) -> bool {
+        self.rejected.contains(&(index, begin))
+    }
+
+    /// Whether the peer rejected any block of the piece since its state
+    /// last changed.
+    pub fn rejected_piece(&self, index: u32) -> bool {
+        self.rejected.iter().any(|&(piece, _)| piece == index)
+    }
+
+    /// Requests a block from the peer.
+    pub fn send(
+        &mut self,
+        handshake: &mut HandShake,
+        index: u32,
+        begin: u32,
+        length: u32,
+    ) -> Result<(), Error> {
+        // tracked before sending, so a failed send still gets released
+        self.in_flight
+            .insert((index, begin), (length, Instant::now()));
+        handshake.send_message(&Message::Request {
+            index,
+            begin,
+            length,
+        })?;
+        self.window.on_request();
+
+        Ok(())
+    }
+
+    /// Stops waiting for a block, e.g. one another peer delivered first.
+    pub fn forget(&mut self, index: u32, begin: u32) {
+        self.in_flight.remove(&(index, begin));
+    }
+
+    /// Updates the requests for a message `handshake` just read. Fails
+    /// with `NotFound` once the peer rejected too many requests.
+    pub fn on_message<'a>(
+        &mut self,
+        message: &'a Message,
+        handshake: &HandShake,
+    ) -> Result<RequestUpdate<'a>, Error> {
+        if matches!(
+            message,
+            Message::Choke | Message::Unchoke | Message::AllowedFast(_)
+        ) {
+            self.rejected.clear();
+        }
+
+        match message {
+            // blocks may arrive in any order; ones we didn't ask for (or no
+            // longer wait for) are dropped
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                let Some(&(length, sent)) = self.in_flight.get(&(*index, *begin)) else {
+                    return Ok(RequestUpdate::None);
+                };
+                if block.len() != length as usize {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Block at {} of piece {} has the wrong length", begin, index),
+                    ));
+                }
+
+                self.in_flight.remove(&(*index, *begin));
+                self.window.on_block(block.len(), sent.elapsed());
+                Ok(RequestUpdate::Block {
+                    index: *index,
+                    begin: *begin,
+                    data: block,
+                })
+            }
+            Message::RejectRequest { index, begin, .. } => {
+                let Some((length, _)) = self.in_flight.remove(&(*index, *begin)) else {
+                    return Ok(RequestUpdate::None);
+                };
+
+                // a choke rejects everything not allowed-fast, which is
+                // expected rather than a refusal
+                if !handshake.choked || handshake.allowed_fast.contains(index) {
+                    self.rejected.insert((*index, *begin));
+                    self.rejects += 1;
+                    if self.rejects >= MAX_REJECTS {
+                        return Err(Error::new(
+                            ErrorKind::NotFound,
+                            format!("Peer rejected {} requests", self.rejects),
+                        ));
+                    }
+                }
+                Ok(RequestUpdate::Dropped(vec![(*index, *begin, length)]))
+            }
+            // without the fast extension a choke silently drops requests
+            Message::Choke if !handshake.fast_enabled() => {
+                let mut dropped: Vec<(u32, u32, u32)> = self
+                    .in_flight
+                    .drain()
+                    .map(|((index, begin), (length, _))| (index, begin, length))
+                    .collect();
+                dropped.sort_unstable();
+                Ok(RequestUpdate::Dropped(dropped))
+            }
+            Message::Extended {
+                id: EXTENDED_HANDSHAKE_ID,
+                ..
+            } => {
+                if let Some(reqq) = handshake.extensions.peer_handshake().and_then(|h| h.reqq) {
+                    self.window.limit_to(reqq.max(1) as usize);
+                }
+                Ok(RequestUpdate::None)
+            }
+            _ => Ok(RequestUpdate::None),
+        }
+    }
+}
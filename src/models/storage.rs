@@ -0,0 +1,117 @@
+use std::fs::{self, OpenOptions};
+use std::io::{Error, ErrorKind, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+use super::info::Info;
+
+pub struct StorageFile {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Maps the torrent's byte stream onto the files it describes.
+///
+/// A single-file torrent is stored at `out` itself; a multi-file torrent
+/// gets a directory `out/<name>` holding its files, so pieces that straddle
+/// a file boundary are split across the files they cover.
+pub struct Storage {
+    pub files: Vec<StorageFile>,
+    piece_length: u64,
+}
+
+impl Storage {
+    pub fn new(info: &Info, out: &Path) -> Result<Storage, Error> {
+        let mut files = Vec::new();
+
+        match &info.files {
+            Some(file_infos) => {
+                let root = out.join(safe_component(&info.name)?);
+                let mut offset = 0;
+
+                for file_info in file_infos {
+                    let mut path = root.clone();
+                    for component in &file_info.path {
+                        path.push(safe_component(component)?);
+                    }
+
+                    files.push(StorageFile {
+                        path,
+                        offset,
+                        length: file_info.length as u64,
+                    });
+                    offset += file_info.length as u64;
+                }
+            }
+            None => files.push(StorageFile {
+                path: out.to_path_buf(),
+                offset: 0,
+                length: info.total_length(),
+            }),
+        }
+
+        Ok(Storage {
+            files,
+            piece_length: info.piece_length,
+        })
+    }
+
+    /// Creates every file (and its directories) at its final size.
+    pub fn allocate(&self) -> Result<(), Error> {
+        for file in &self.files {
+            if let Some(parent) = file.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let handle = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&file.path)?;
+            handle.set_len(file.length)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn write_piece(&self, piece_index: usize, data: &[u8]) -> Result<(), Error> {
+        let piece_start = piece_index as u64 * self.piece_length;
+
+        for (file, range) in self.spans(piece_start, data.len() as u64) {
+            let mut handle = OpenOptions::new().write(true).open(&file.path)?;
+            handle.seek(SeekFrom::Start(range.0 - file.offset))?;
+            handle.write_all(
+                &data[(range.0 - piece_start) as usize..(range.1 - piece_start) as usize],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // the files overlapping [start, start + length) with the absolute byte
+    // range each one covers
+    fn spans(&self, start: u64, length: u64) -> impl Iterator<Item = (&StorageFile, (u64, u64))> {
+        let end = start + length;
+
+        self.files.iter().filter_map(move |file| {
+            let file_end = file.offset + file.length;
+            let from = start.max(file.offset);
+            let to = end.min(file_end);
+
+            (from < to).then_some((file, (from, to)))
+        })
+    }
+}
+
+// a path component from the torrent must not escape the download directory
+fn safe_component(component: &str) -> Result<&str, Error> {
+    let mut components = Path::new(component).components();
+
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(component),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Unsafe path component in torrent: {:?}", component),
+        )),
+    }
+}
@@ -1,8 +1,17 @@
 use std::fmt::Write;
+use std::io::{Error, ErrorKind};
 
 use reqwest::blocking::Client;
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
 
 use super::peers::Peer;
+use crate::bencode_de;
+
+#[derive(Deserialize)]
+struct TrackerResponse {
+    peers: ByteBuf,
+}
 
 pub struct TrackerRequest {
     url: String,
@@ -35,7 +44,20 @@ impl TrackerRequest {
         }
     }
 
-    pub fn get_peers(&self) -> Vec<Peer> {
+    /// Whether `url` is a tracker this client can announce to; only HTTP(S)
+    /// trackers are supported.
+    pub fn is_supported(url: &str) -> bool {
+        url.starts_with("http://") || url.starts_with("https://")
+    }
+
+    pub fn get_peers(&self) -> Result<Vec<Peer>, Error> {
+        if !TrackerRequest::is_supported(&self.url) {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("Unsupported tracker {:?}", self.url),
+            ));
+        }
+
         let client = Client::new();
         let encoded_info_hash = self.info_hash.iter().fold(String::new(), |mut output, b| {
             let _ = write!(output, "%{b:02X}");
@@ -51,37 +73,29 @@ impl TrackerRequest {
             ("compact", "1"),
         ];
 
-        let params = serde_urlencoded::to_string(&params).expect("Failed to encode params");
+        let params = serde_urlencoded::to_string(params).expect("Failed to encode params");
 
         let url = format!("{}?{}&info_hash={}", self.url, params, encoded_info_hash);
 
-        let response = client.get(&url).send().expect("Failed to send request");
-        let body = response
-            .bytes()
-            .expect("Failed to get response body")
-            .to_vec();
-
-        let peers = serde_bencode::from_bytes::<serde_bencode::value::Value>(&body)
-            .expect("Failed to decode response");
+        let tracker_error = |err: reqwest::Error| {
+            let kind = if err.is_timeout() {
+                ErrorKind::TimedOut
+            } else {
+                ErrorKind::NotConnected
+            };
+            Error::new(kind, format!("Tracker request failed: {}", err))
+        };
+        let response = client.get(&url).send().map_err(tracker_error)?;
+        let body = response.bytes().map_err(tracker_error)?.to_vec();
 
-        let peers: Vec<Peer> = match peers {
-            serde_bencode::value::Value::Dict(dict) => {
-                let peers = dict.get("peers".as_bytes()).expect("No peers found");
+        let response = bencode_de::from_bytes::<TrackerResponse>(&body)?;
 
-                match peers {
-                    serde_bencode::value::Value::Bytes(b) => b
-                        .chunks_exact(6)
-                        .map(|chunk| Peer {
-                            ip: format!("{}.{}.{}.{}", chunk[0], chunk[1], chunk[2], chunk[3]),
-                            port: u16::from_be_bytes([chunk[4], chunk[5]]),
-                        })
-                        .collect(),
-                    _ => panic!("Expected dict"),
-                }
-            }
-            _ => panic!("Expected dict"),
-        };
+        let peers: Vec<Peer> = response
+            .peers
+            .chunks_exact(6)
+            .filter_map(Peer::from_compact)
+            .collect();
 
-        peers
+        Ok(peers)
     }
 }
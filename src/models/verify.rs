@@ -0,0 +1,103 @@
+use std::io::Error;
+use std::path::PathBuf;
+
+use super::hasher::{HashProgress, PieceHasher};
+use super::info::Info;
+use super::storage::Storage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceState {
+    Complete,
+    /// Some of the piece's bytes are not on disk.
+    Missing,
+    /// All bytes are there but the hash doesn't match.
+    Corrupt,
+}
+
+pub struct FileReport {
+    pub path: PathBuf,
+    pub complete: usize,
+    pub missing: usize,
+    pub corrupt: usize,
+}
+
+pub struct VerifyReport {
+    pub pieces: Vec<PieceState>,
+    pub files: Vec<FileReport>,
+}
+
+impl VerifyReport {
+    pub fn count(&self, state: PieceState) -> usize {
+        self.pieces.iter().filter(|&&piece| piece == state).count()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces
+            .iter()
+            .all(|&piece| piece == PieceState::Complete)
+    }
+
+    /// Complete pieces as a peer-protocol bitfield (high bit first).
+    pub fn bitfield(&self) -> Vec<u8> {
+        let mut bitfield = vec![0; self.pieces.len().div_ceil(8)];
+
+        for (index, &piece) in self.pieces.iter().enumerate() {
+            if piece == PieceState::Complete {
+                bitfield[index / 8] |= 0x80 >> (index % 8);
+            }
+        }
+
+        bitfield
+    }
+}
+
+/// Hashes what is on disk for `storage` and compares it with `info.pieces`.
+pub fn verify_storage(
+    info: &Info,
+    storage: &Storage,
+    progress: impl FnMut(&HashProgress),
+) -> Result<VerifyReport, Error> {
+    let hashes = PieceHasher::with_available_parallelism().hash_files(
+        &storage.files,
+        info.piece_length,
+        progress,
+    )?;
+
+    let pieces: Vec<PieceState> = (0..info.piece_count())
+        .map(|index| match hashes.get(index).copied().flatten() {
+            Some(hash) if hash == info.piece_hash(index) => PieceState::Complete,
+            Some(_) => PieceState::Corrupt,
+            None => PieceState::Missing,
+        })
+        .collect();
+
+    let files = storage
+        .files
+        .iter()
+        .map(|file| {
+            let mut report = FileReport {
+                path: file.path.clone(),
+                complete: 0,
+                missing: 0,
+                corrupt: 0,
+            };
+
+            if file.length > 0 {
+                let first = (file.offset / info.piece_length) as usize;
+                let last = ((file.offset + file.length - 1) / info.piece_length) as usize;
+
+                for piece in pieces.iter().take(last + 1).skip(first) {
+                    match piece {
+                        PieceState::Complete => report.complete += 1,
+                        PieceState::Missing => report.missing += 1,
+                        PieceState::Corrupt => report.corrupt += 1,
+                    }
+                }
+            }
+
+            report
+        })
+        .collect();
+
+    Ok(VerifyReport { pieces, files })
+}